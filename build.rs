@@ -0,0 +1,35 @@
+//! Generates the table of rule files compiled into the binary (`embed-rules`).
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=NSCWRS_EMBED_DIR");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("cargo sets OUT_DIR"));
+    let mut table = String::from("&[\n");
+    if env::var_os("CARGO_FEATURE_EMBED_RULES").is_some() {
+        let dir = env::var("NSCWRS_EMBED_DIR").unwrap_or_else(|_| "wrappers".to_string());
+        let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").expect("cargo sets it")).join(dir);
+        println!("cargo:rerun-if-changed={}", dir.display());
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap_or_else(|err| panic!("cannot read {}: {}", dir.display(), err))
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+        for path in files {
+            let Some(program) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            println!("cargo:rerun-if-changed={}", path.display());
+            table.push_str(&format!(
+                "    ({:?}, include_str!({:?})),\n",
+                program,
+                path.canonicalize().unwrap_or(path.clone())
+            ));
+        }
+    }
+    table.push(']');
+    fs::write(out_dir.join("embedded_rules.rs"), table).expect("cannot write embedded_rules.rs");
+}
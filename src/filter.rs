@@ -0,0 +1,172 @@
+//! `filter-cmd:` rules: matched text rewritten by an external command.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+/// How long a persistent command has to answer a match before it is given up on
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `cmd` run through the platform shell
+pub fn shell_command(cmd: &str) -> Command {
+    #[cfg(unix)]
+    let (shell, flag) = ("sh", "-c");
+    #[cfg(windows)]
+    let (shell, flag) = ("cmd", "/C");
+    let mut command = Command::new(shell);
+    command.arg(flag).arg(cmd);
+    command
+}
+
+/// Runs the commands of `filter-cmd:` rules. By default every match starts the
+/// command anew with the match as its whole input. With `persistent`, each command
+/// is started once and kept running, fed one match per line and expected to answer
+/// each with one line, so it must not buffer its output (`sed -u`, not `sed`). One
+/// that doesn't answer within `ANSWER_TIMEOUT` is stopped.
+#[derive(Default)]
+pub(crate) struct CommandFilters {
+    pub(crate) persistent: bool,
+    /// `None` once the command failed to start or stopped answering
+    running: HashMap<String, Option<Coprocess>>,
+}
+
+struct Coprocess {
+    child: Child,
+    stdin: ChildStdin,
+    /// The command's output lines, read on a thread of their own
+    answers: Receiver<String>,
+}
+
+impl CommandFilters {
+    /// What `cmd` makes of `text`, without the trailing newline. `None` when it
+    /// couldn't be run or failed, leaving the text as it was.
+    pub(crate) fn apply(&mut self, cmd: &str, text: &str) -> Option<String> {
+        let output = if self.persistent {
+            self.ask(cmd, text)?
+        } else {
+            run_once(cmd, text)?
+        };
+        let output = output.strip_suffix('\n').unwrap_or(&output);
+        Some(output.strip_suffix('\r').unwrap_or(output).to_string())
+    }
+
+    fn ask(&mut self, cmd: &str, text: &str) -> Option<String> {
+        let slot = self
+            .running
+            .entry(cmd.to_string())
+            .or_insert_with(|| Coprocess::start(cmd));
+        let coprocess = slot.as_mut()?;
+        let answer = writeln!(coprocess.stdin, "{}", text)
+            .and_then(|_| coprocess.stdin.flush())
+            .ok()
+            .and_then(|_| coprocess.answers.recv_timeout(ANSWER_TIMEOUT).ok());
+        if answer.is_none() {
+            // Ended, broken or too slow: the rule's matches stay as they are from now on
+            let _ = coprocess.child.kill();
+            let _ = coprocess.child.wait();
+            *slot = None;
+        }
+        answer
+    }
+}
+
+impl Coprocess {
+    fn start(cmd: &str) -> Option<Self> {
+        let mut child = shell_command(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let stdin = child.stdin.take()?;
+        let stdout = BufReader::new(child.stdout.take()?);
+        let (sender, answers) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stdout.lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Some(Coprocess {
+            child,
+            stdin,
+            answers,
+        })
+    }
+}
+
+impl Drop for CommandFilters {
+    fn drop(&mut self) {
+        for coprocess in self.running.values_mut().flatten() {
+            let _ = coprocess.child.kill();
+            let _ = coprocess.child.wait();
+        }
+    }
+}
+
+/// Start `cmd` with `text` as its input and return its output, if it succeeded
+fn run_once(cmd: &str, text: &str) -> Option<String> {
+    let mut child = shell_command(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let mut stdin = child.stdin.take()?;
+    // Written from a thread of its own, so a command that answers before it has
+    // read everything can't fill its pipe while we are still writing. A command
+    // that doesn't read its input at all is fine too, the write error is ignored.
+    let output = thread::scope(|scope| {
+        scope.spawn(move || stdin.write_all(text.as_bytes()));
+        child.wait_with_output()
+    })
+    .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_started_per_match_rewrite_it() {
+        let mut filters = CommandFilters::default();
+        assert_eq!(
+            filters.apply("tr a-z A-Z", "hello").as_deref(),
+            Some("HELLO")
+        );
+        // Only the final newline goes
+        assert_eq!(
+            filters.apply("printf 'a\\n\\nb\\r\\n'", "x").as_deref(),
+            Some("a\n\nb")
+        );
+        assert_eq!(filters.apply("exit 1", "hello"), None);
+        // A command that doesn't read its input is fine too
+        assert_eq!(
+            filters.apply("echo fixed", "hello").as_deref(),
+            Some("fixed")
+        );
+    }
+
+    #[test]
+    fn persistent_commands_answer_match_after_match() {
+        let mut filters = CommandFilters {
+            persistent: true,
+            running: HashMap::new(),
+        };
+        let cmd = "while read -r line; do echo \"<$line>\"; done";
+        assert_eq!(filters.apply(cmd, "a").as_deref(), Some("<a>"));
+        assert_eq!(filters.apply(cmd, "b").as_deref(), Some("<b>"));
+        assert_eq!(filters.running.len(), 1);
+        // One that ends stops being asked
+        assert_eq!(filters.apply("exit 0", "a"), None);
+        assert_eq!(filters.apply("exit 0", "b"), None);
+        assert!(filters.running["exit 0"].is_none());
+    }
+}
@@ -0,0 +1,21 @@
+//! Regex based coloring of program output.
+//!
+//! The `nscwrs` binary wraps programs and colors what they print; the same
+//! rules can be applied from other code through [`Colorizer`].
+
+mod colorize;
+mod filter;
+mod render;
+mod rules;
+#[cfg(feature = "syntax")]
+mod syntax;
+
+pub use colorize::{ColoredLine, Colorizer, Match};
+pub use filter::shell_command;
+pub use render::{AnsiRenderer, ColorRenderer, HtmlRenderer, MarkerRenderer, PlainRenderer, Style};
+pub use rules::{
+    Diagnostic, Rule, RuleSet, Severity, StreamScope, fnv1a, is_light_background, load_color_rules,
+    parse_color_rules, parse_color_token, set_regex_size_limit,
+};
+#[cfg(feature = "syntax")]
+pub use syntax::SyntaxHighlighter;
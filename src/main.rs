@@ -1,234 +1,3910 @@
 use atty::Stream;
 use colored::*;
+use nscwrs::{
+    ColoredLine, Colorizer, MarkerRenderer, Rule, RuleSet, StreamScope, fnv1a, is_light_background,
+    load_color_rules, parse_color_rules, parse_color_token, set_regex_size_limit, shell_command,
+};
 use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::fs;
-use std::io::{self, BufRead, BufReader, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, LineWriter, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use unicode_width::UnicodeWidthChar;
 
 const WRAP_DIR: &str = "./wrappers";
+/// Rule file in the wrapper directory loaded beneath every program's rules
+const GLOBAL_RULES: &str = "_global";
+/// Searched when `PATH` is unset or empty (cron, minimal containers)
+#[cfg(unix)]
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+#[cfg(windows)]
+const DEFAULT_PATH: &str = r"C:\Windows\System32;C:\Windows";
+#[cfg(unix)]
+const PATH_SEPARATOR: char = ':';
+#[cfg(windows)]
+const PATH_SEPARATOR: char = ';';
+/// Used when `PATHEXT` is unset
+#[cfg(windows)]
+const DEFAULT_PATHEXT: &str = ".COM;.EXE;.BAT;.CMD";
 
-struct Rule {
-    regex: Regex,
-    fg_color: Color,
-    bg_color: Option<Color>,
+/// Rule files shared by all users, layered beneath the user's own
+#[cfg(unix)]
+const SYSTEM_DIR: &str = "/etc/nscwrs";
+
+/// Commented examples written by `--new`, below a shebang line
+const WRAPPER_TEMPLATE: &str = include_str!("wrapper_template");
+/// Rules for `--diff`
+const DIFF_RULES: &str = include_str!("diff_rules");
+/// Rules for `--logfmt`
+const LOGFMT_RULES: &str = include_str!("logfmt_rules");
+
+/// Rule files compiled in with the `embed-rules` feature, as (program, content).
+/// Empty without it.
+const EMBEDDED_RULES: &[(&str, &str)] = include!(concat!(env!("OUT_DIR"), "/embedded_rules.rs"));
+
+/// Most lines `--tail` holds in memory
+const TAIL_MAX: usize = 1_000_000;
+
+/// Default `--read-buffer`, the same as `BufReader`'s
+const READ_BUFFER: usize = 8 * 1024;
+
+/// Exit code when `--max-output` cut the program off
+const TRUNCATED_EXIT_CODE: i32 = 3;
+
+/// Exit code when stdout was closed under us, as if `SIGPIPE` had ended us
+const BROKEN_PIPE_EXIT_CODE: i32 = 128 + 13;
+
+/// Flags given to nscwrs itself, before the wrapper path (e.g. on the shebang line)
+#[derive(Clone, Default)]
+struct Options {
+    /// Report line/byte counts and throughput on stderr at exit
+    timing: bool,
+    /// Refuse to run with a rule file that has any problems
+    strict: bool,
+    /// Fold runs of identical lines into one line with an `(xN)` suffix
+    collapse: bool,
+    /// Rule sub-directory to prefer, falls back to `NSCWRS_PROFILE`
+    profile: Option<String>,
+    color: ColorChoice,
+    /// Shell command run (detached) when a `bell:true` rule rings
+    notify_cmd: Option<String>,
+    /// Also color the program's stderr
+    stderr: bool,
+    /// Skip the system-wide rule file
+    no_system: bool,
+    /// Skip the wrapper directory's `_global` rule file
+    no_global: bool,
+    /// Later rules win on overlapping matches, like `@cascade last`
+    last_wins: bool,
+    /// Delimiters put around matches when output isn't colored (`--marker OPEN CLOSE`)
+    marker: Option<(String, String)>,
+    /// Start each `filter-cmd:` command once instead of for every match
+    persistent_filter: bool,
+    /// Also write stdout to this file (`--tee FILE`)
+    tee: Option<PathBuf>,
+    /// Also record stdout, with timing, as an asciinema cast (`--cast FILE`)
+    cast: Option<PathBuf>,
+    /// Unix socket to send a JSON event to for every line a rule matched
+    events_socket: Option<PathBuf>,
+    /// Line to show what the rules make of (`--explain LINE`)
+    explain: Option<String>,
+    /// Rule file used instead of the one named like the program (`--rules FILE`)
+    rules: Option<PathBuf>,
+    /// Color this captured log instead of running a program (`--replay FILE`)
+    replay: Option<PathBuf>,
+    /// Keep the escapes in the `--tee` copy instead of stripping them
+    tee_color: bool,
+    /// Highlight lines arriving longer than this after the previous one
+    slow_threshold: Option<Duration>,
+    /// Background used for slow lines (`--slow-color`)
+    slow_color: Option<Color>,
+    /// Color the columns of a table by the header's column starts (`--table`),
+    /// with these colors in turn (`--table-colors`)
+    table: bool,
+    table_colors: Vec<Color>,
+    /// Lines of an earlier run; those not among them are highlighted (`--baseline FILE`)
+    baseline: Option<PathBuf>,
+    /// Background used for new lines (`--baseline-color`)
+    baseline_color: Option<Color>,
+    /// Replace the baseline with this run's lines at exit
+    update_baseline: bool,
+    /// Only print the last N lines, once the program is done
+    tail: Option<usize>,
+    /// Lines shown around those `keep:`/`drop:` rules let through (`--context N`)
+    context: usize,
+    /// Drop the program's own colors before applying the rules
+    strip_existing: bool,
+    /// What unknown and `default` fg colors resolve to, over `@default-color`
+    default_color: Option<Color>,
+    /// Exit 0 unless a `fail-on` rule matched, whatever the program returned
+    exit_on_rules: bool,
+    /// Exit with at least the code of the worst `severity:` match
+    exit_on_severity: bool,
+    /// Print output ending in a carriage return right away (progress bars, spinners)
+    cr_flush: bool,
+    /// Look for the real program in the wrapper directory too
+    search_wrapper_dir: bool,
+    /// Warn about rules whose fg and bg are hard to tell apart
+    check_contrast: bool,
+    /// Layer `<program>.<ext>` rules picked by the extension of a file argument
+    by_ext: bool,
+    /// Accent color derived from the program name, for `default` colors and our own
+    /// stderr lines
+    auto_accent: bool,
+    /// Send a terminal notification when the program is done
+    notify: bool,
+    /// Filter programs the output runs through before it is colored (`--pipe`)
+    pipe: Vec<Vec<String>>,
+    /// Language the output is syntax-highlighted as before the rules run
+    syntax: Option<String>,
+    /// Print a keepalive line after this long without output
+    heartbeat: Option<Duration>,
+    /// Expand tabs to spaces with tab stops this far apart, before the rules run
+    expand_tabs: Option<usize>,
+    /// Repeat the active color every N columns, for terminals that drop it at soft wraps
+    rewrap_safe: Option<usize>,
+    /// Reset the colors at the end of every colored line (`--full-reset`)
+    full_reset: bool,
+    /// What happens to blank lines (`--squeeze-blank`, `--strip-blank`)
+    blank_lines: Option<BlankLines>,
+    /// Show the program's control characters as `^[` instead of passing them on
+    neutralize_escapes: bool,
+    /// Stop the program once it has printed this many bytes to stdout
+    max_output: Option<u64>,
+    /// Capacity of the buffers the program's output is read through
+    read_buffer: Option<usize>,
+    /// Color the program's whole stdout as one record once it has exited
+    flatten: bool,
+    /// Largest compiled size of a rule file's regex, in bytes
+    regex_size_limit: Option<usize>,
+    /// What `format:thousands` rules group digits with
+    thousands_sep: Option<char>,
+    /// Extra `pattern=color` rules, applied after the rule files (`--highlight`)
+    highlights: Vec<String>,
+    /// Environment variables set for the real program, after the rule files' `@env`
+    setenv: Vec<(String, String)>,
+    /// Re-run the program up to this many times while it exits non-zero
+    retries: u32,
+    /// Create a wrapper for this program from the template and exit
+    new: Option<String>,
+    /// Let `--new` replace an existing file
+    force: bool,
+    /// Print the parsed rules of the file given instead of a wrapper and exit
+    dump_rules: bool,
+    /// Report how the wrapper resolves and exit without running the program
+    verify: bool,
+    /// Only load the rule files given and exit non-zero if any has a problem
+    check: bool,
+    /// Time each rule of the file given over a sample read from stdin and exit
+    profile_rules: bool,
+    /// Run the built-in end-to-end check and exit
+    selftest: bool,
+    /// Print swatches of every color a rule file can name and exit
+    color_test: bool,
+    /// Color a unified diff read from stdin with the built-in diff rules
+    diff: bool,
+    /// Color the keys and values of logfmt lines, beneath the rule file's rules
+    logfmt: bool,
+}
+
+/// `--color=auto|always|never`
+#[derive(Clone, Copy, Default, PartialEq)]
+enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+}
+
+/// `--squeeze-blank` or `--strip-blank`. Blank means empty or only whitespace,
+/// after `--strip-existing`.
+#[derive(Clone, Copy)]
+enum BlankLines {
+    /// Runs of blank lines are written as one, like `cat -s`
+    Squeeze,
+    /// Blank lines are not written at all
+    Strip,
+}
+
+impl BlankLines {
+    /// Whether a line is written, for a blank one given whether the last line
+    /// written was blank too
+    fn keep(self, blank: bool, after_blank: bool) -> bool {
+        match self {
+            BlankLines::Squeeze => !(blank && after_blank),
+            BlankLines::Strip => !blank,
+        }
+    }
+}
+
+/// The one place that decides whether a stream gets colored, in this order:
+///
+/// 1. `--color=always` / `--color=never` win outright, so `--color=always > out.log` keeps
+///    the escapes in the file.
+/// 2. With `auto`, a non-empty `NO_COLOR` turns color off and a `CLICOLOR_FORCE` other
+///    than `0` turns it on.
+/// 3. Otherwise color only when the stream is a terminal; redirecting to a file or a
+///    pipe gives plain text.
+fn should_colorize(
+    stream: Stream,
+    choice: ColorChoice,
+    env: impl Fn(&str) -> Option<String>,
+) -> bool {
+    match choice {
+        ColorChoice::Always => return true,
+        ColorChoice::Never => return false,
+        ColorChoice::Auto => {}
+    }
+    if env("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    if env("CLICOLOR_FORCE").is_some_and(|value| !value.is_empty() && value != "0") {
+        return true;
+    }
+    atty::is(stream)
+}
+
+/// Whether `args` are what the kernel passes for a shebang line: the text after the
+/// interpreter as the first argument, followed by the path of the script whose first
+/// line holds it. A first argument typed on the command line with spaces in it
+/// (`"--highlight=foo bar=red"`) doesn't come with such a script.
+fn started_from_shebang(args: &[String]) -> bool {
+    let (Some(flags), Some(script)) = (args.first(), args.get(1)) else {
+        return false;
+    };
+    let mut head = [0; 256];
+    let Ok(read) = File::open(script).and_then(|mut file| file.read(&mut head)) else {
+        return false;
+    };
+    let head = String::from_utf8_lossy(&head[..read]);
+    head.strip_prefix("#!")
+        .and_then(|shebang| shebang.lines().next())
+        .and_then(|shebang| shebang.trim().split_once(char::is_whitespace))
+        .is_some_and(|(_, rest)| rest.trim() == flags.trim())
+}
+
+/// Split our own flags from the wrapper path and the arguments for the real program.
+///
+/// Options end at the first argument that isn't a flag, or at `--`. A shebang line
+/// hands everything after the interpreter over as one argument, so when we were
+/// started from one, a first argument holding several flags is split on whitespace.
+fn parse_args(mut args: Vec<String>) -> (Options, Vec<String>) {
+    if args
+        .first()
+        .is_some_and(|arg| arg.starts_with("--") && arg.contains(char::is_whitespace))
+        && started_from_shebang(&args)
+    {
+        let first = args.remove(0);
+        for (idx, flag) in first.split_whitespace().enumerate() {
+            args.insert(idx, flag.to_string());
+        }
+    }
+
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    let mut rest = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if !arg.starts_with("--") {
+            rest.push(arg);
+            break;
+        }
+
+        // Values are given either as `--flag=value` or as the next argument
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .unwrap_or_else(|| {
+                    eprintln!("Missing value for {}", flag);
+                    std::process::exit(2);
+                })
+        };
+
+        match flag.as_str() {
+            "--timing" => options.timing = true,
+            "--strict" => options.strict = true,
+            "--collapse" => options.collapse = true,
+            "--stderr" => options.stderr = true,
+            "--no-system" => options.no_system = true,
+            "--no-global" => options.no_global = true,
+            "--update-baseline" => options.update_baseline = true,
+            "--dump-rules" => options.dump_rules = true,
+            "--verify" => options.verify = true,
+            "--check" => options.check = true,
+            "--profile-rules" => options.profile_rules = true,
+            "--selftest" => options.selftest = true,
+            "--color-test" => options.color_test = true,
+            "--diff" => options.diff = true,
+            "--logfmt" => options.logfmt = true,
+            "--flatten" => options.flatten = true,
+            "--new" => options.new = Some(value()),
+            "--force" => options.force = true,
+            "--last-wins" => options.last_wins = true,
+            "--tee-color" => options.tee_color = true,
+            "--strip-existing" => options.strip_existing = true,
+            "--exit-on-rules" => options.exit_on_rules = true,
+            "--exit-on-severity" => options.exit_on_severity = true,
+            "--cr-flush" => options.cr_flush = true,
+            "--search-wrapper-dir" => options.search_wrapper_dir = true,
+            "--check-contrast" => options.check_contrast = true,
+            "--auto-accent" => options.auto_accent = true,
+            "--by-ext" => options.by_ext = true,
+            "--notify" => options.notify = true,
+            "--tee" => options.tee = Some(PathBuf::from(value())),
+            "--cast" => options.cast = Some(PathBuf::from(value())),
+            "--events-socket" => options.events_socket = Some(PathBuf::from(value())),
+            "--rules" => options.rules = Some(PathBuf::from(value())),
+            "--replay" => options.replay = Some(PathBuf::from(value())),
+            "--explain" => options.explain = Some(value()),
+            "--profile" => options.profile = Some(value()),
+            "--notify-cmd" => options.notify_cmd = Some(value()),
+            "--highlight" => options.highlights.push(value()),
+            "--setenv" => {
+                let var = value();
+                match var.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => {
+                        options.setenv.push((key.to_string(), value.to_string()));
+                    }
+                    _ => {
+                        eprintln!("Invalid --setenv value: {} (expected KEY=VALUE)", var);
+                        std::process::exit(2);
+                    }
+                }
+            }
+            "--persistent-filter" => options.persistent_filter = true,
+            "--marker" => {
+                let open = value();
+                let close = args.next().unwrap_or_else(|| {
+                    eprintln!("Missing closing marker for --marker");
+                    std::process::exit(2);
+                });
+                options.marker = Some((open, close));
+            }
+            "--regex-size-limit" => {
+                let limit = value();
+                options.regex_size_limit = match parse_size(&limit) {
+                    Some(limit) if limit > 0 => Some(limit as usize),
+                    _ => {
+                        eprintln!("Invalid --regex-size-limit value: {} (e.g. 1M)", limit);
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--thousands-sep" => {
+                let sep = value();
+                let mut chars = sep.chars();
+                options.thousands_sep = match (chars.next(), chars.next()) {
+                    (Some(sep), None) => Some(sep),
+                    _ => {
+                        eprintln!(
+                            "Invalid --thousands-sep value: {:?} (expected one character)",
+                            sep
+                        );
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--max-output" => {
+                let max = value();
+                options.max_output = match parse_size(&max) {
+                    Some(max) if max > 0 => Some(max),
+                    _ => {
+                        eprintln!("Invalid --max-output value: {} (e.g. 10M, 4096)", max);
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--read-buffer" => {
+                let size = value();
+                options.read_buffer = match parse_size(&size).map(usize::try_from) {
+                    Some(Ok(size)) if size > 0 => Some(size),
+                    _ => {
+                        eprintln!("Invalid --read-buffer value: {} (e.g. 64K)", size);
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--heartbeat" => {
+                let interval = value();
+                options.heartbeat = match parse_duration(&interval) {
+                    Some(interval) if !interval.is_zero() => Some(interval),
+                    _ => {
+                        eprintln!("Invalid --heartbeat value: {} (e.g. 30s, 500ms)", interval);
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--slow-threshold" => {
+                let threshold = value();
+                options.slow_threshold = Some(parse_duration(&threshold).unwrap_or_else(|| {
+                    eprintln!(
+                        "Invalid --slow-threshold value: {} (e.g. 500ms, 2s)",
+                        threshold
+                    );
+                    std::process::exit(2);
+                }));
+            }
+            "--default-color" => {
+                let color = value();
+                options.default_color = Some(parse_color_token(&color).unwrap_or_else(|| {
+                    eprintln!("Invalid --default-color value: {}", color);
+                    std::process::exit(2);
+                }));
+            }
+            "--slow-color" => {
+                let color = value();
+                options.slow_color = Some(parse_color_token(&color).unwrap_or_else(|| {
+                    eprintln!("Invalid --slow-color value: {}", color);
+                    std::process::exit(2);
+                }));
+            }
+            "--baseline" => options.baseline = Some(PathBuf::from(value())),
+            "--baseline-color" => {
+                let color = value();
+                options.baseline_color = Some(parse_color_token(&color).unwrap_or_else(|| {
+                    eprintln!("Invalid --baseline-color value: {}", color);
+                    std::process::exit(2);
+                }));
+            }
+            "--pipe" => {
+                let pipeline = value();
+                options.pipe = parse_pipeline(&pipeline).unwrap_or_else(|err| {
+                    eprintln!("Invalid --pipe value: {} ({})", pipeline, err);
+                    std::process::exit(2);
+                });
+            }
+            "--syntax" => options.syntax = Some(value()),
+            "--neutralize-escapes" => options.neutralize_escapes = true,
+            "--full-reset" => options.full_reset = true,
+            "--table" => options.table = true,
+            "--table-colors" => {
+                let colors = value();
+                options.table_colors = colors
+                    .split(',')
+                    .map(|color| parse_color_token(color.trim()))
+                    .collect::<Option<_>>()
+                    .filter(|colors: &Vec<Color>| !colors.is_empty())
+                    .unwrap_or_else(|| {
+                        eprintln!(
+                            "Invalid --table-colors value: {} (e.g. cyan,yellow)",
+                            colors
+                        );
+                        std::process::exit(2);
+                    });
+            }
+            "--squeeze-blank" => options.blank_lines = Some(BlankLines::Squeeze),
+            "--strip-blank" => options.blank_lines = Some(BlankLines::Strip),
+            "--expand-tabs" => {
+                let width = value();
+                options.expand_tabs = match width.parse() {
+                    Ok(width) if width > 0 => Some(width),
+                    _ => {
+                        eprintln!("Invalid --expand-tabs value: {} (columns, e.g. 8)", width);
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--rewrap-safe" => {
+                let cols = value();
+                options.rewrap_safe = match cols.parse() {
+                    Ok(cols) if cols > 0 => Some(cols),
+                    _ => {
+                        eprintln!("Invalid --rewrap-safe value: {} (columns, e.g. 80)", cols);
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--tail" => {
+                let count = value();
+                options.tail = match count.parse() {
+                    Ok(count) if count > 0 && count <= TAIL_MAX => Some(count),
+                    _ => {
+                        eprintln!("Invalid --tail value: {} (1 to {})", count, TAIL_MAX);
+                        std::process::exit(2);
+                    }
+                };
+            }
+            "--context" => {
+                let count = value();
+                options.context = count.parse().unwrap_or_else(|_| {
+                    eprintln!("Invalid --context value: {} (lines, e.g. 2)", count);
+                    std::process::exit(2);
+                });
+            }
+            "--retries" => {
+                let count = value();
+                options.retries = count.parse().unwrap_or_else(|_| {
+                    eprintln!("Invalid --retries value: {}", count);
+                    std::process::exit(2);
+                });
+            }
+            "--color" => {
+                let choice = value();
+                options.color = ColorChoice::parse(&choice).unwrap_or_else(|| {
+                    eprintln!("Invalid --color value: {} (auto, always, never)", choice);
+                    std::process::exit(2);
+                });
+            }
+            _ => {
+                eprintln!("Unknown option: {}", flag);
+                std::process::exit(2);
+            }
+        }
+    }
+    rest.extend(args);
+
+    (options, rest)
+}
+
+/// Width of the terminal: `COLUMNS` if set, else what the terminal on stdout or
+/// stderr reports
+fn terminal_width() -> Option<usize> {
+    let columns = env::var("COLUMNS")
+        .ok()
+        .and_then(|cols| cols.trim().parse().ok());
+    columns.filter(|&cols| cols > 0).or_else(tty_width)
+}
+
+#[cfg(unix)]
+fn tty_width() -> Option<usize> {
+    [libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .find_map(|fd| {
+            let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+            // SAFETY: TIOCGWINSZ only writes a `winsize` through the pointer
+            let ok = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0;
+            (ok && size.ws_col > 0).then_some(size.ws_col as usize)
+        })
+}
+
+/// Only `COLUMNS` is looked at on Windows
+#[cfg(windows)]
+fn tty_width() -> Option<usize> {
+    None
+}
+
+/// A byte count, bare or with a `K`, `M` or `G` suffix (powers of 1024)
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, unit) = match value.char_indices().last()? {
+        (idx, 'K' | 'k') => (&value[..idx], 1 << 10),
+        (idx, 'M' | 'm') => (&value[..idx], 1 << 20),
+        (idx, 'G' | 'g') => (&value[..idx], 1 << 30),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// `500ms`, `2s` or a bare number of milliseconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    if let Some(millis) = value.strip_suffix("ms") {
+        return millis.trim().parse().ok().map(Duration::from_millis);
+    }
+    if let Some(secs) = value.strip_suffix('s') {
+        return secs
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64);
+    }
+    value.trim().parse().ok().map(Duration::from_millis)
+}
+
+/// Program to wrap when nscwrs was started under another name (`ls` linked to
+/// the binary, busybox style), taken from `argv0` as invoked. Hardlinks work as
+/// well as symlinks since the name isn't resolved. `None` when it is `nscwrs`.
+fn multicall_name(argv0: &str) -> Option<String> {
+    let name = Path::new(argv0).file_name()?.to_str()?;
+    let name = name.strip_suffix(env::consts::EXE_SUFFIX).unwrap_or(name);
+    (name != "nscwrs" && !name.is_empty()).then(|| name.to_string())
+}
+
+fn get_wrapped_program(args: &[String]) -> Option<String> {
+    args.first().and_then(|arg| {
+        Path::new(&arg)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_string())
+    })
+}
+
+/// Wrapper directory, `NSCWRS_WRAP_DIR` takes precedence over the compiled-in default
+fn resolve_wrap_dir() -> String {
+    env::var("NSCWRS_WRAP_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| WRAP_DIR.to_string())
+}
+
+/// Rule file for `program`: `<wrap_dir>/<profile>/<program>` if a profile is
+/// selected and has one, `<wrap_dir>/<program>` otherwise
+fn find_rule_file(wrap_dir: &str, profile: Option<&str>, program: &str) -> PathBuf {
+    if let Some(profile) = profile {
+        let candidate = Path::new(wrap_dir).join(profile).join(program);
+        if candidate.is_file() {
+            return candidate;
+        }
+    }
+    Path::new(wrap_dir).join(program)
+}
+
+/// System-wide rule file for `program`: `/etc/nscwrs/<program>`, or
+/// `%ProgramData%\nscwrs\<program>` on Windows
+#[cfg(unix)]
+fn system_rule_file(program: &str) -> Option<PathBuf> {
+    Some(Path::new(SYSTEM_DIR).join(program))
+}
+
+#[cfg(windows)]
+fn system_rule_file(program: &str) -> Option<PathBuf> {
+    let data_dir = env::var_os("ProgramData")?;
+    Some(Path::new(&data_dir).join("nscwrs").join(program))
+}
+
+/// `--by-ext`: the `<program>.<ext>` rule file next to `rule_file` for the first
+/// argument whose extension has one, e.g. `cargo.rs` for `rustc src/main.rs`
+fn find_ext_rule_file(rule_file: &Path, program: &str, program_args: &[String]) -> Option<PathBuf> {
+    let dir = rule_file.parent()?;
+    program_args
+        .iter()
+        .filter(|arg| !arg.starts_with('-'))
+        .filter_map(|arg| Path::new(arg).extension())
+        .map(|ext| dir.join(format!("{}.{}", program, ext.to_string_lossy())))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Rule file for the subcommand, the first argument not starting with `-`:
+/// `<rule_file>.d/<subcommand>`, or `<rule_file>/<subcommand>` when the rule file
+/// is a directory, e.g. `cargo.d/test` for `cargo test`
+fn find_subcommand_rule_file(rule_file: &Path, program_args: &[String]) -> Option<PathBuf> {
+    let subcommand = program_args.iter().find(|arg| !arg.starts_with('-'))?;
+    // A plain name only, `../x` or `a/b` mustn't reach outside the directory
+    if subcommand.is_empty() || subcommand.starts_with('.') || subcommand.contains(['/', '\\']) {
+        return None;
+    }
+    let dir = if rule_file.is_dir() {
+        rule_file.to_path_buf()
+    } else {
+        let mut dir = rule_file.as_os_str().to_owned();
+        dir.push(".d");
+        PathBuf::from(dir)
+    };
+    Some(dir.join(subcommand)).filter(|candidate| candidate.is_file())
+}
+
+/// Print the problems found in `path`. With `--strict`, list them and exit instead.
+/// `--check-contrast` warnings are printed too, but never stop the run.
+fn report_diagnostics(path: &Path, rule_set: &RuleSet, options: &Options) {
+    if options.strict && !rule_set.diagnostics.is_empty() {
+        eprintln!(
+            "{} problem(s) in {}, not running:",
+            rule_set.diagnostics.len(),
+            path.display()
+        );
+        for diagnostic in &rule_set.diagnostics {
+            eprintln!("  {}", diagnostic);
+        }
+        std::process::exit(1);
+    }
+    print_diagnostics(path, rule_set, options);
+}
+
+/// `--verify`: a rule file that was read, with its problems, on stdout
+fn report_verified(path: &Path, rule_set: &RuleSet, options: &Options) {
+    match rule_set.diagnostics.len() {
+        0 => println!(
+            "rules: {} ({} rule(s))",
+            path.display(),
+            rule_set.rules().len()
+        ),
+        problems => {
+            println!(
+                "rules: {} ({} rule(s), {} problem(s))",
+                path.display(),
+                rule_set.rules().len(),
+                problems
+            );
+            for diagnostic in &rule_set.diagnostics {
+                println!("  {}", diagnostic);
+            }
+        }
+    }
+    if options.check_contrast {
+        for warning in rule_set.contrast_warnings() {
+            println!("  {}", warning);
+        }
+    }
+}
+
+/// `--verify`: where `target` resolved to, and the executables of that name that
+/// were passed over on the way. True when it was found.
+fn report_program(target: &str, found: Option<&Path>, passed_over: &[(PathBuf, &str)]) -> bool {
+    for (path, reason) in passed_over {
+        println!("passed over: {} ({})", path.display(), reason);
+    }
+    match found {
+        Some(path) => println!("program: {}", path.display()),
+        None => println!("program: '{}' not found on PATH", target),
+    }
+    found.is_some()
+}
+
+/// Every problem of `rule_set` on stderr, and contrast warnings with `--check-contrast`
+fn print_diagnostics(path: &Path, rule_set: &RuleSet, options: &Options) {
+    for diagnostic in &rule_set.diagnostics {
+        eprintln!("{}: {}", path.display(), diagnostic);
+    }
+    if options.check_contrast {
+        for warning in rule_set.contrast_warnings() {
+            eprintln!("{}: {}", path.display(), warning);
+        }
+    }
+}
+
+/// Resolve the fallback color of a loaded file: `--default-color`, else the file's
+/// `@default-color`, else the `--auto-accent` color, else whatever reads well on the
+/// terminal background
+fn apply_default_color(rule_set: &mut RuleSet, options: &Options, accent: Option<Color>) {
+    let color = options
+        .default_color
+        .or_else(|| match rule_set.default_color {
+            Some(_) => None, // Already applied by the loader
+            None => {
+                accent.or_else(|| background_default_color(env::var("COLORFGBG").ok().as_deref()))
+            }
+        });
+    if let Some(color) = color {
+        rule_set.set_default_color(color);
+    }
+}
+
+/// Colors `--auto-accent` picks from: readable on dark and light backgrounds alike
+const ACCENT_COLORS: [Color; 12] = [
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::BrightRed,
+    Color::BrightGreen,
+    Color::BrightYellow,
+    Color::BrightBlue,
+    Color::BrightMagenta,
+    Color::BrightCyan,
+];
+
+/// `--auto-accent`: a color that only depends on `program`, so each program's output
+/// looks the same every time and different programs usually differ
+fn accent_color(program: &str) -> Color {
+    ACCENT_COLORS[(fnv1a(program.bytes()) % ACCENT_COLORS.len() as u64) as usize]
+}
+
+/// Black on light backgrounds, as told by `COLORFGBG`. `None` keeps white, also
+/// when the background is unknown.
+fn background_default_color(colorfgbg: Option<&str>) -> Option<Color> {
+    is_light_background(colorfgbg).then_some(Color::Black)
+}
+
+/// Locate the real program in PATH (excluding wrappers directory unless
+/// `search_wrapper_dir`)
+fn find_real_program(
+    program: &str,
+    wrap_dir: &str,
+    search_wrapper_dir: bool,
+    passed_over: &mut Vec<(PathBuf, &'static str)>,
+) -> Option<PathBuf> {
+    let path_var = match env::var("PATH") {
+        Ok(path) if !path.is_empty() => path,
+        _ => {
+            eprintln!(
+                "Warning: PATH is unset or empty, searching {} instead",
+                DEFAULT_PATH
+            );
+            DEFAULT_PATH.to_string()
+        }
+    };
+    // Our wrapper directory plus `NSCWRS_SKIP_DIRS` (other shim layers)
+    let skip_list = env::var("NSCWRS_SKIP_DIRS").unwrap_or_default();
+    let skipped: Vec<PathBuf> = std::iter::once(wrap_dir)
+        .filter(|_| !search_wrapper_dir)
+        .chain(skip_list.split(PATH_SEPARATOR))
+        .filter(|dir| !dir.is_empty())
+        .map(canonical_dir)
+        .collect();
+    for dir in path_var.split(PATH_SEPARATOR) {
+        let candidates = executable_candidates(Path::new(dir), program)
+            .into_iter()
+            .filter(|candidate| is_executable(candidate));
+        if skipped.contains(&canonical_dir(dir)) {
+            passed_over.extend(candidates.map(|candidate| (candidate, "skipped directory")));
+            continue;
+        }
+        for candidate in candidates {
+            if runs_nscwrs(&candidate) {
+                passed_over.push((candidate, "runs nscwrs"));
+            } else {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+/// Whether running `path` would start nscwrs again: it is our own binary (e.g. a
+/// symlink or hardlink to it), or a script whose shebang names it, as wrappers do.
+/// Picking either as the real program would make every run spawn another one.
+fn runs_nscwrs(path: &Path) -> bool {
+    let Ok(own) = env::current_exe() else {
+        return false;
+    };
+    let is_own = |path: &Path| same_file(path, &own);
+    if is_own(path) {
+        return true;
+    }
+    let mut head = [0; 256];
+    let Ok(read) = File::open(path).and_then(|mut file| file.read(&mut head)) else {
+        return false;
+    };
+    let head = String::from_utf8_lossy(&head[..read]);
+    head.strip_prefix("#!")
+        .and_then(|shebang| shebang.lines().next())
+        .and_then(|shebang| shebang.split_whitespace().next())
+        .is_some_and(|interpreter| is_own(Path::new(interpreter)))
+}
+
+/// Whether both paths are the same file, after symlinks and through hardlinks
+#[cfg(unix)]
+fn same_file(first: &Path, second: &Path) -> bool {
+    match (first.metadata(), second.metadata()) {
+        (Ok(first), Ok(second)) => first.dev() == second.dev() && first.ino() == second.ino(),
+        _ => false,
+    }
+}
+
+/// Whether both paths are the same file after symlinks are resolved
+#[cfg(windows)]
+fn same_file(first: &Path, second: &Path) -> bool {
+    match (first.canonicalize(), second.canonicalize()) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => false,
+    }
+}
+
+/// `dir` with symlinks and `..` resolved, so differently spelled paths compare
+/// equal. Directories that don't exist are kept as written.
+fn canonical_dir(dir: &str) -> PathBuf {
+    Path::new(dir)
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(dir))
+}
+
+/// Paths `program` may resolve to inside `dir`
+#[cfg(unix)]
+fn executable_candidates(dir: &Path, program: &str) -> Vec<PathBuf> {
+    vec![dir.join(program)]
+}
+
+/// Paths `program` may resolve to inside `dir`: the name as given if it already has
+/// an extension, then the name with each `PATHEXT` extension appended
+#[cfg(windows)]
+fn executable_candidates(dir: &Path, program: &str) -> Vec<PathBuf> {
+    let pathext = env::var("PATHEXT").unwrap_or_else(|_| DEFAULT_PATHEXT.to_string());
+    let mut candidates = Vec::new();
+    if Path::new(program).extension().is_some() {
+        candidates.push(dir.join(program));
+    }
+    candidates.extend(
+        pathext
+            .split(';')
+            .filter(|ext| !ext.is_empty())
+            .map(|ext| dir.join(format!("{}{}", program, ext))),
+    );
+    candidates
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Windows has no execute bit, the `PATHEXT` extension is what makes a file runnable
+#[cfg(windows)]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// `keep:`/`drop:` rules with `--context N`: the N lines before and after each
+/// line let through are shown as well, dimmed and without the rules' colors, like
+/// `grep -C`. A `--` line separates groups that aren't contiguous.
+struct ContextLines {
+    lines: usize,
+    use_color: bool,
+    /// Filtered out lines that may still turn out to come before a shown one
+    before: VecDeque<(String, Ending)>,
+    /// Filtered out lines still to show after the last one let through
+    after: usize,
+    /// A line was shown and at least one has been left out since
+    gap: bool,
+    shown: bool,
+}
+
+impl ContextLines {
+    fn new(lines: usize, use_color: bool) -> Self {
+        ContextLines {
+            lines,
+            use_color,
+            before: VecDeque::new(),
+            after: 0,
+            gap: false,
+            shown: false,
+        }
+    }
+
+    /// Lines to write for `text`, colored as shown unless `dropped`
+    fn push(&mut self, text: String, ending: Ending, dropped: bool) -> Vec<(String, Ending)> {
+        if dropped {
+            if self.after > 0 {
+                self.after -= 1;
+                return vec![(self.context(&text), ending)];
+            }
+            if self.lines > 0 {
+                if self.before.len() == self.lines {
+                    self.before.pop_front();
+                    self.gap = true;
+                }
+                self.before.push_back((self.context(&text), ending));
+            } else {
+                self.gap = true;
+            }
+            return Vec::new();
+        }
+        let mut lines = Vec::new();
+        if self.lines > 0 && self.shown && self.gap {
+            let separator = if self.use_color {
+                "--".dimmed().to_string()
+            } else {
+                "--".to_string()
+            };
+            lines.push((separator, Ending::Lf));
+        }
+        lines.extend(self.before.drain(..));
+        lines.push((text, ending));
+        self.after = self.lines;
+        self.gap = false;
+        self.shown = true;
+        lines
+    }
+
+    /// A filtered out line as context: its text (redactions included) dimmed
+    fn context(&self, text: &str) -> String {
+        let text = strip_ansi(text);
+        if self.use_color {
+            text.dimmed().to_string()
+        } else {
+            text.into_owned()
+        }
+    }
+}
+
+/// Colors `--table` gives to the columns in turn, unless `--table-colors` is given
+const TABLE_COLORS: [Color; 5] = [
+    Color::Cyan,
+    Color::Yellow,
+    Color::Green,
+    Color::Magenta,
+    Color::Blue,
+];
+
+/// `--table`: the columns of aligned output such as `docker ps`, found where the
+/// header's words start after two or more spaces, each colored in turn. The
+/// rules' colors win: only text they left unstyled gets a column color.
+struct Table {
+    colors: Vec<Color>,
+    /// Character columns the table's columns start at, once the header was seen
+    starts: Option<Vec<usize>>,
+}
+
+impl Table {
+    fn new(colors: &[Color]) -> Self {
+        let colors = if colors.is_empty() {
+            TABLE_COLORS.to_vec()
+        } else {
+            colors.to_vec()
+        };
+        Table {
+            colors,
+            starts: None,
+        }
+    }
+
+    /// `colored`, the output for `line`, with its columns colored. The first line
+    /// that isn't blank is the header and is left as it is.
+    fn color(&mut self, line: &str, colored: String) -> String {
+        let Some(starts) = &self.starts else {
+            if !line.trim().is_empty() {
+                self.starts = Some(column_starts(&strip_ansi(line)));
+            }
+            return colored;
+        };
+        if starts.len() < 2 {
+            return colored;
+        }
+        let visible: Vec<char> = strip_ansi(&colored).chars().collect();
+        let cells = table_cells(&visible, starts);
+        color_cells(&colored, &cells, &self.colors)
+    }
+}
+
+/// Where the words of a table header start: at the first one, and after every run
+/// of two or more spaces (or a tab), so `CONTAINER ID` stays one column
+fn column_starts(header: &str) -> Vec<usize> {
+    let chars: Vec<char> = header.chars().collect();
+    let mut starts = Vec::new();
+    let mut spaces = 0;
+    for (idx, &ch) in chars.iter().enumerate() {
+        if ch == '\t' {
+            spaces += 2;
+        } else if ch == ' ' {
+            spaces += 1;
+        } else {
+            if starts.is_empty() || spaces >= 2 {
+                starts.push(idx);
+            }
+            spaces = 0;
+        }
+    }
+    starts
+}
+
+/// The character range of each cell of `row`, by column, without the spaces around
+/// it. A value starting a little before its column, after two or more spaces,
+/// still belongs to it; one running past its column's end pushes the next column's
+/// start back to after the next run of two or more spaces.
+fn table_cells(row: &[char], starts: &[usize]) -> Vec<(usize, usize)> {
+    let blank = |idx: usize| row[idx].is_whitespace();
+    let after_gap = |idx: usize| idx >= 2 && blank(idx - 1) && blank(idx - 2) && !blank(idx);
+    let mut begins: Vec<usize> = Vec::with_capacity(starts.len());
+    for &start in starts {
+        let previous = begins.last().copied().unwrap_or(0);
+        let mut begin = start.max(previous).min(row.len());
+        if begin > 0 && begin < row.len() && !blank(begin - 1) {
+            let word = (previous..begin)
+                .rev()
+                .find(|&idx| blank(idx))
+                .map_or(previous, |idx| idx + 1);
+            begin = if word > previous && after_gap(word) {
+                word
+            } else {
+                (begin + 1..row.len())
+                    .find(|&idx| after_gap(idx))
+                    .unwrap_or(row.len())
+            };
+        }
+        begins.push(begin);
+    }
+    begins
+        .iter()
+        .enumerate()
+        .map(|(column, &begin)| {
+            let end = begins.get(column + 1).copied().unwrap_or(row.len());
+            let first = (begin..end).find(|&idx| !blank(idx));
+            let last = (begin..end).rev().find(|&idx| !blank(idx));
+            match (first, last) {
+                (Some(first), Some(last)) => (first, last + 1),
+                _ => (begin, begin),
+            }
+        })
+        .collect()
+}
+
+/// `colored` with the characters of each cell that no escape styles already put
+/// in the cell's color
+fn color_cells(colored: &str, cells: &[(usize, usize)], colors: &[Color]) -> String {
+    let mut result = String::with_capacity(colored.len() * 2);
+    // Whether an escape of the rules or the program styles what follows
+    let mut styled = false;
+    let mut open: Option<usize> = None;
+    let mut col = 0;
+    let mut rest = colored;
+    while let Some(ch) = rest.chars().next() {
+        if let Some(params) = rest.strip_prefix("\x1b[") {
+            let len = params
+                .find(|ch: char| ('\x40'..='\x7e').contains(&ch))
+                .map_or(params.len(), |end| end + 1);
+            let escape = &rest[..len + 2];
+            if let Some(sgr) = escape.strip_suffix('m') {
+                styled = !matches!(&sgr[2..], "" | "0");
+            }
+            if open.take().is_some() {
+                result.push_str("\x1b[0m");
+            }
+            result.push_str(escape);
+            rest = &rest[escape.len()..];
+            continue;
+        }
+        let cell = cells
+            .iter()
+            .position(|&(start, end)| start <= col && col < end)
+            .filter(|_| !styled);
+        if open != cell {
+            if open.is_some() {
+                result.push_str("\x1b[0m");
+            }
+            if let Some(cell) = cell {
+                let color = colors[cell % colors.len()];
+                result.push_str(&format!("\x1b[{}m", color.to_fg_str()));
+            }
+            open = cell;
+        }
+        result.push(ch);
+        rest = &rest[ch.len_utf8()..];
+        col += 1;
+    }
+    if open.is_some() {
+        result.push_str("\x1b[0m");
+    }
+    result
+}
+
+/// `--collapse`: holds a line back until it is known how often it repeats.
+///
+/// Lines are compared after coloring, so identical text that was colored
+/// differently (e.g. by a `@when` section) is not folded together.
+#[derive(Default)]
+struct Collapser {
+    held: Option<String>,
+    count: usize,
+}
+
+impl Collapser {
+    /// Queue `line`, returning the previous run if `line` ends it
+    fn push(&mut self, line: String, use_color: bool) -> Option<String> {
+        if self.held.as_ref() == Some(&line) {
+            self.count += 1;
+            return None;
+        }
+        let finished = self.finish(use_color);
+        self.held = Some(line);
+        self.count = 1;
+        finished
+    }
+
+    /// Flush the run in progress
+    fn finish(&mut self, use_color: bool) -> Option<String> {
+        let line = self.held.take()?;
+        if self.count < 2 {
+            return Some(line);
+        }
+        let suffix = format!("(x{})", self.count);
+        if use_color {
+            Some(format!("{} {}", line, suffix.dimmed()))
+        } else {
+            Some(format!("{} {}", line, suffix))
+        }
+    }
+}
+
+/// Minimum quiet time between two bells, so a flood of errors rings only once
+const BELL_DEBOUNCE: Duration = Duration::from_secs(5);
+/// Shortest time between two runs of the same `run:` command
+const HOOK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files that `sink:` rules append matched lines to, opened on first use and
+/// shared by both streams
+#[derive(Default)]
+struct Sinks {
+    /// `None` once opening or writing failed, so the error is reported once
+    files: HashMap<PathBuf, Option<File>>,
+}
+
+impl Sinks {
+    fn write(&mut self, paths: &[PathBuf], line: &str) {
+        for path in paths {
+            let file = self.files.entry(path.clone()).or_insert_with(|| {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| {
+                        eprintln!("nscwrs: cannot open sink {} ({})", path.display(), err)
+                    })
+                    .ok()
+            });
+            if let Some(open) = file
+                && let Err(err) = writeln!(open, "{}", line)
+            {
+                eprintln!("nscwrs: writing sink {} failed ({})", path.display(), err);
+                *file = None;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        for (path, file) in &mut self.files {
+            if let Some(open) = file
+                && let Err(err) = open.flush()
+            {
+                eprintln!("nscwrs: writing sink {} failed ({})", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Commands started for `run:` rules and `--notify-cmd`, which nscwrs doesn't
+/// wait for. The ones that ended are reaped whenever another is started, so they
+/// don't linger as zombies for the rest of a long run.
+#[derive(Default)]
+struct Detached {
+    children: Vec<Child>,
+}
+
+impl Detached {
+    fn push(&mut self, child: Child) {
+        self.children
+            .retain_mut(|child| !matches!(child.try_wait(), Ok(Some(_))));
+        self.children.push(child);
+    }
+}
+
+/// Runs the commands of `run:` rules, each at most once per `HOOK_INTERVAL` so a
+/// burst of matches doesn't start a burst of processes
+#[derive(Default)]
+struct Hooks {
+    last_run: HashMap<String, Instant>,
+    running: Detached,
+}
+
+impl Hooks {
+    fn run(&mut self, commands: &[String], line: &str) {
+        for cmd in commands {
+            if self
+                .last_run
+                .get(cmd)
+                .is_some_and(|ran| ran.elapsed() < HOOK_INTERVAL)
+            {
+                continue;
+            }
+            self.last_run.insert(cmd.clone(), Instant::now());
+            // Like `--notify-cmd`: the line in NSCWRS_LINE, the child is not waited for
+            let spawned = shell_command(cmd)
+                .env("NSCWRS_LINE", line)
+                .stdin(Stdio::null())
+                .spawn();
+            match spawned {
+                Ok(child) => self.running.push(child),
+                Err(err) => eprintln!("nscwrs: failed to run '{}' ({})", cmd, err),
+            }
+        }
+    }
+}
+
+/// `--events-socket`: a JSON line per matched line for a listener on a Unix
+/// socket, written on a thread of its own. Events are dropped while the listener
+/// lags behind, so a slow consumer never holds the program up. The writer ends
+/// once every `Events` is dropped.
+#[derive(Clone)]
+struct Events {
+    sender: mpsc::SyncSender<String>,
+}
+
+impl Events {
+    /// Connect to the listener at `path`, along with the writer thread
+    #[cfg(unix)]
+    fn connect(path: &Path) -> io::Result<(Self, JoinHandle<()>)> {
+        // Events waiting for the listener at most, newer ones are dropped
+        const BACKLOG: usize = 1024;
+        let socket = std::os::unix::net::UnixStream::connect(path)?;
+        // Writes never wait for the listener, see `EventWriter`
+        socket.set_nonblocking(true)?;
+        let (sender, receiver) = mpsc::sync_channel::<String>(BACKLOG);
+        let writer = thread::spawn(move || {
+            let mut writer = EventWriter {
+                socket,
+                pending: Vec::new(),
+            };
+            for event in receiver {
+                if writer.send(&event).is_err() {
+                    return; // The listener went away
+                }
+            }
+            writer.finish();
+        });
+        Ok((Events { sender }, writer))
+    }
+
+    #[cfg(not(unix))]
+    fn connect(_path: &Path) -> io::Result<(Self, JoinHandle<()>)> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are not available on this platform",
+        ))
+    }
+
+    /// Queue the event for `line`, if any rule matched it. `rules` are those of
+    /// the colorizer that produced `processed`.
+    fn send(&self, stream: &str, line: &str, processed: &ColoredLine, rules: &[Rule]) {
+        if processed.matches.is_empty() {
+            return;
+        }
+        // The line as shown, so a `redact:` rule keeps its secret here as well
+        let mut shown = String::with_capacity(line.len());
+        let mut last_pos = 0;
+        let mut matches = Vec::new();
+        for found in &processed.matches {
+            let rule = &rules[found.rule];
+            shown.push_str(&line[last_pos..found.start]);
+            let start = shown.len();
+            shown.push_str(&rule.shown(&line[found.start..found.end]));
+            matches.push(serde_json::json!({
+                "start": start,
+                "end": shown.len(),
+                "rule_line": rule.line(),
+                "pattern": rule.pattern(),
+            }));
+            last_pos = found.end;
+        }
+        shown.push_str(&line[last_pos..]);
+        let event = serde_json::json!({
+            "stream": stream,
+            "line": shown,
+            "matches": matches,
+        });
+        // Only fails when the queue is full or the listener is gone
+        let _ = self.sender.try_send(event.to_string());
+    }
+}
+
+/// Copies our stdin to the program's on a thread of its own, while the output is
+/// read on others, so a filter (`... | nscwrs wrappers/jq .`) can take in more than
+/// a pipe holds before its output is read without either side waiting on the
+/// other. One thread serves every attempt of `--retries` and writes to the
+/// program of the current one.
+struct StdinPump {
+    program: Arc<(Mutex<PumpTarget>, Condvar)>,
+}
+
+/// Where `StdinPump` writes
+#[derive(Default)]
+struct PumpTarget {
+    /// Stdin of the running program, `None` before it started and once it took no
+    /// more
+    stdin: Option<ChildStdin>,
+    /// Our stdin has ended, later programs get an empty one
+    ended: bool,
+}
+
+impl StdinPump {
+    fn start() -> Self {
+        let program: Arc<(Mutex<PumpTarget>, Condvar)> = Arc::default();
+        let pump = StdinPump {
+            program: Arc::clone(&program),
+        };
+        thread::spawn(move || {
+            let (target, started) = &*program;
+            let mut stdin = io::stdin().lock();
+            let mut chunk = vec![0; 64 * 1024];
+            loop {
+                let read = match stdin.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                // Input read ahead waits for the program, also for the next
+                // `--retries` attempt
+                let mut target = started
+                    .wait_while(target.lock().unwrap(), |target| target.stdin.is_none())
+                    .unwrap();
+                // A program that exited or closed its stdin takes no more, what it
+                // didn't read is lost as it would be with a shared stdin
+                if let Some(stdin) = target.stdin.as_mut()
+                    && stdin.write_all(&chunk[..read]).is_err()
+                {
+                    target.stdin = None;
+                }
+            }
+            // Closing the program's stdin tells it the input is complete
+            let mut target = target.lock().unwrap();
+            target.ended = true;
+            target.stdin = None;
+        });
+        pump
+    }
+
+    /// Write what comes next to `stdin`, the newly started program's
+    fn feed(&self, stdin: Option<ChildStdin>) {
+        let (target, started) = &*self.program;
+        let mut target = target.lock().unwrap();
+        target.stdin = stdin.filter(|_| !target.ended);
+        started.notify_one();
+    }
+}
+
+/// The `--events-socket` end of the writer thread. An event the socket can't take
+/// right away is dropped, so a listener that is slow, or stopped reading, only
+/// misses events and keeps getting the later ones once it catches up.
+#[cfg(unix)]
+struct EventWriter {
+    socket: std::os::unix::net::UnixStream,
+    /// The rest of an event the socket only took part of, which goes out before
+    /// any other so the listener never gets half a line
+    pending: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl EventWriter {
+    /// Write `event`, or drop it while an earlier one is still pending. Only fails
+    /// when the listener is gone.
+    fn send(&mut self, event: &str) -> io::Result<()> {
+        self.flush()?;
+        if self.pending.is_empty() {
+            self.pending.extend_from_slice(event.as_bytes());
+            self.pending.push(b'\n');
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write as much of the pending event as the socket takes without waiting
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.socket.write(&self.pending) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.pending.drain(..written);
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Give the listener a second for the rest of a pending event. A listener that
+    /// stopped reading must not keep nscwrs from exiting.
+    fn finish(mut self) {
+        if !self.pending.is_empty()
+            && self.socket.set_nonblocking(false).is_ok()
+            && self
+                .socket
+                .set_write_timeout(Some(Duration::from_secs(1)))
+                .is_ok()
+        {
+            let _ = self.socket.write_all(&self.pending);
+        }
+    }
+}
+
+/// Rings the terminal bell (and runs `--notify-cmd`) for `bell:true` matches
+struct Bell {
+    notify_cmd: Option<String>,
+    last_rung: Option<Instant>,
+    notifying: Detached,
+}
+
+impl Bell {
+    fn ring(&mut self, line: &str) {
+        if self
+            .last_rung
+            .is_some_and(|rung| rung.elapsed() < BELL_DEBOUNCE)
+        {
+            return;
+        }
+        self.last_rung = Some(Instant::now());
+
+        eprint!("\x07");
+        let _ = io::stderr().flush();
+        if let Some(cmd) = &self.notify_cmd {
+            // The matched line is handed over in NSCWRS_LINE, the child is not waited for
+            let spawned = shell_command(cmd)
+                .env("NSCWRS_LINE", line)
+                .stdin(Stdio::null())
+                .spawn();
+            match spawned {
+                Ok(child) => self.notifying.push(child),
+                Err(err) => eprintln!("Failed to run notify command '{}': {}", cmd, err),
+            }
+        }
+    }
+}
+
+/// Every rule file of a run and what is added to them, kept to load the rules
+/// again on `SIGHUP`
+struct RuleSources {
+    program: String,
+    /// The program's arguments, for subcommand rules and `arg:`
+    args: Vec<String>,
+    wrapper_path: PathBuf,
+    /// Compiled-in rules standing in for a missing `wrapper_path`
+    embedded: Option<&'static str>,
+    subcommand_path: Option<PathBuf>,
+    ext_path: Option<PathBuf>,
+    system_path: Option<PathBuf>,
+    global_path: Option<PathBuf>,
+    /// `NSCWRS_RULES` entries
+    env_rules: Vec<String>,
+    accent: Option<Color>,
+}
+
+impl RuleSources {
+    /// The rules of the run, each file's problems handed to `report`
+    fn load(&self, options: &Options, report: fn(&Path, &RuleSet, &Options)) -> RuleSet {
+        // User rules come first so they win over the system-wide baseline
+        let mut rule_set = match self.embedded {
+            Some(content) => {
+                let rule_set = parse_color_rules(content, Path::new("."));
+                let source = format!("<embedded {}>", self.program);
+                report(Path::new(&source), &rule_set, options);
+                rule_set
+            }
+            None => {
+                let rule_set = load_color_rules(&self.wrapper_path);
+                report(&self.wrapper_path, &rule_set, options);
+                rule_set
+            }
+        };
+        rule_set.last_wins |= options.last_wins;
+        // The rules see the text, not the highlighter's escapes
+        rule_set.preserve_escapes |= options.syntax.is_some();
+        apply_default_color(&mut rule_set, options, self.accent);
+        // The subcommand's rules win over the program's
+        if let Some(subcommand_path) = &self.subcommand_path {
+            let mut subcommand_rules = load_color_rules(subcommand_path);
+            report(subcommand_path, &subcommand_rules, options);
+            apply_default_color(&mut subcommand_rules, options, self.accent);
+            subcommand_rules.merge(rule_set);
+            rule_set = subcommand_rules;
+        }
+        // Rules for the kind of file being worked on are the most specific of all
+        if let Some(ext_path) = &self.ext_path {
+            let mut ext_rules = load_color_rules(ext_path);
+            report(ext_path, &ext_rules, options);
+            apply_default_color(&mut ext_rules, options, self.accent);
+            ext_rules.merge(rule_set);
+            rule_set = ext_rules;
+        }
+        if let Some(system_path) = &self.system_path {
+            let mut system_rules = load_color_rules(system_path);
+            report(system_path, &system_rules, options);
+            apply_default_color(&mut system_rules, options, self.accent);
+            rule_set.merge(system_rules);
+        }
+        if let Some(global_path) = &self.global_path {
+            let mut global_rules = load_color_rules(global_path);
+            report(global_path, &global_rules, options);
+            apply_default_color(&mut global_rules, options, self.accent);
+            rule_set.merge(global_rules);
+        }
+        if options.logfmt {
+            let mut logfmt_rules = parse_color_rules(LOGFMT_RULES, Path::new("."));
+            apply_default_color(&mut logfmt_rules, options, self.accent);
+            rule_set.merge(logfmt_rules);
+        }
+        for entry in &self.env_rules {
+            if let Err(err) = rule_set.add_highlight(entry) {
+                eprintln!("Invalid NSCWRS_RULES entry {}: {}", entry, err);
+                std::process::exit(2);
+            }
+        }
+        for highlight in &options.highlights {
+            if let Err(err) = rule_set.add_highlight(highlight) {
+                eprintln!("Invalid --highlight {}: {}", highlight, err);
+                std::process::exit(2);
+            }
+        }
+        rule_set.for_program(&self.program);
+        rule_set.for_args(&self.args);
+        if let Some(sep) = options.thousands_sep {
+            rule_set.set_thousands_sep(sep);
+        }
+        // Unknown (e.g. output to a file): every rule stays
+        if let Some(cols) = terminal_width() {
+            rule_set.for_width(cols);
+        }
+        rule_set
+    }
+}
+
+/// Set by the `SIGHUP` handler, see `Reload`
+static SIGHUP: AtomicBool = AtomicBool::new(false);
+
+/// Have `SIGHUP` ask for the rules to be reloaded instead of ending nscwrs. The
+/// program gets the default handling back when it is started.
+#[cfg(unix)]
+fn watch_sighup() {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        SIGHUP.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+}
+
+/// There is no `SIGHUP` on Windows
+#[cfg(windows)]
+fn watch_sighup() {}
+
+/// The `SIGINT` or `SIGTERM` that asked us to stop, 0 if none did
+static STOP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// Process id of the running child, 0 between runs, for passing `SIGTERM` on
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Have `SIGINT` and `SIGTERM` end the child instead of nscwrs, so that its last
+/// output is still read and the `--tee`, `--cast` and sink files are complete. The
+/// terminal sends `SIGINT` to the child itself; `SIGTERM` is passed on to it.
+#[cfg(unix)]
+fn watch_stop_signals() {
+    extern "C" fn on_stop(signal: libc::c_int) {
+        STOP_SIGNAL.store(signal, Ordering::Relaxed);
+        let pid = CHILD_PID.load(Ordering::Relaxed);
+        if signal == libc::SIGTERM && pid > 0 {
+            // SAFETY: kill is async-signal-safe
+            unsafe {
+                libc::kill(pid, libc::SIGTERM);
+            }
+        }
+    }
+    // SAFETY: the handler only uses atomics and kill, which are async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, on_stop as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_stop as *const () as libc::sighandler_t);
+    }
+}
+
+/// Ctrl-C reaches the child on its own on Windows
+#[cfg(windows)]
+fn watch_stop_signals() {}
+
+/// `SIGHUP` reloads the rules, which are kept only if they load without problems.
+/// The stream that notices the signal first does the loading, and every stream
+/// switches to the new rules before its next line.
+struct Reload {
+    sources: RuleSources,
+    options: Options,
+    rule_set: Mutex<RuleSet>,
+    /// Bumped by every reload that was kept
+    generation: AtomicUsize,
+}
+
+impl Reload {
+    fn current(&self) -> RuleSet {
+        self.rule_set.lock().unwrap().clone()
+    }
+
+    /// The rules, if they were reloaded since `generation`, which is brought up to date
+    fn newer(&self, generation: &mut usize) -> Option<RuleSet> {
+        if SIGHUP.swap(false, Ordering::Relaxed) {
+            self.reload();
+        }
+        let current = self.generation.load(Ordering::Relaxed);
+        if current == *generation {
+            return None;
+        }
+        *generation = current;
+        Some(self.current())
+    }
+
+    fn reload(&self) {
+        let rule_set = self.sources.load(&self.options, print_diagnostics);
+        if !rule_set.diagnostics.is_empty() {
+            eprintln!(
+                "nscwrs: {} problem(s) in the reloaded rules, keeping the previous ones",
+                rule_set.diagnostics.len()
+            );
+            return;
+        }
+        *self.rule_set.lock().unwrap() = rule_set;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Lines and bytes read from the child's stdout, over all attempts
+#[derive(Default)]
+struct LineCounts {
+    lines: u64,
+    bytes: u64,
+}
+
+/// What one run of the child needs. Every run starts with fresh colorizers, so
+/// `@when` sections don't carry over from a failed attempt into the retry.
+struct Attempt<'a> {
+    reload: Arc<Reload>,
+    options: &'a Options,
+    use_color: bool,
+    use_stderr_color: bool,
+    bell: Arc<Mutex<Bell>>,
+    sinks: Arc<Mutex<Sinks>>,
+    hooks: Arc<Mutex<Hooks>>,
+    tee: Option<Tee>,
+    cast: Option<Cast>,
+    events: Option<Events>,
+    filters: LineFilters,
+    /// Lines of `--baseline`, as the rules see them
+    baseline: Option<HashSet<String>>,
+    /// Stdout lines of the latest run, for `--update-baseline`
+    seen: Vec<String>,
+    /// Lines of the latest run that matched a `fail-on` rule, for `--notify`
+    fail_lines: u64,
+    /// The latest run was stopped by `--max-output`
+    truncated: bool,
+    /// Writing to stdout failed, the reader went away (`nscwrs prog | head`)
+    stdout_closed: bool,
+    /// Feeds our stdin to the program when it isn't a terminal
+    stdin_pump: Option<StdinPump>,
+}
+
+/// How the colorizer of one stream is made, again when `Reload` has new rules
+#[derive(Clone)]
+struct ColorizerMaker {
+    use_color: bool,
+    stream: StreamScope,
+    /// `--marker` delimiters, used without color
+    marker: Option<(String, String)>,
+    /// Matches are reported, for `--events-socket`
+    matches: bool,
+    /// `filter-cmd:` commands are kept running (`--persistent-filter`)
+    persistent_filters: bool,
+}
+
+impl ColorizerMaker {
+    /// Colorizer for the stream, falling back to `--marker` delimiters without color
+    fn make(&self, rule_set: RuleSet) -> Colorizer {
+        let colorizer = match &self.marker {
+            Some((open, close)) if !self.use_color => Colorizer::with_renderer(
+                rule_set,
+                MarkerRenderer {
+                    open: open.clone(),
+                    close: close.clone(),
+                },
+            ),
+            _ => Colorizer::new(rule_set, self.use_color),
+        };
+        let mut colorizer = colorizer.for_stream(self.stream);
+        if self.persistent_filters {
+            colorizer = colorizer.with_persistent_filters();
+        }
+        if self.matches {
+            colorizer.with_matches()
+        } else {
+            colorizer
+        }
+    }
+}
+
+impl<'a> Attempt<'a> {
+    /// What the runs need besides the program, and the `--events-socket` writer to
+    /// wait for. Exits when a file to write can't be opened.
+    fn new(
+        options: &'a Options,
+        reload: Reload,
+        use_color: bool,
+        use_stderr_color: bool,
+    ) -> (Self, Option<JoinHandle<()>>) {
+        let tee = options.tee.as_deref().map(|path| {
+            Tee::create(path, options.tee_color).unwrap_or_else(|err| {
+                eprintln!("Could not create {} ({})", path.display(), err);
+                std::process::exit(1);
+            })
+        });
+        let cast = options.cast.as_deref().map(|path| {
+            Cast::create(path).unwrap_or_else(|err| {
+                eprintln!("Could not create {} ({})", path.display(), err);
+                std::process::exit(1);
+            })
+        });
+        if options.update_baseline && options.baseline.is_none() {
+            eprintln!("--update-baseline needs the file to update, given with --baseline FILE");
+            std::process::exit(2);
+        }
+        let baseline = options
+            .baseline
+            .as_deref()
+            .map(|path| match fs::read_to_string(path) {
+                Ok(content) => content.lines().map(str::to_string).collect(),
+                // The first `--update-baseline` run creates it
+                Err(err) if err.kind() == io::ErrorKind::NotFound && options.update_baseline => {
+                    HashSet::new()
+                }
+                Err(err) => {
+                    eprintln!("Could not read {} ({})", path.display(), err);
+                    std::process::exit(1);
+                }
+            });
+        let mut events_writer = None;
+        let events = options.events_socket.as_deref().map(|path| {
+            let (events, writer) = Events::connect(path).unwrap_or_else(|err| {
+                eprintln!("Could not connect to {} ({})", path.display(), err);
+                std::process::exit(1);
+            });
+            events_writer = Some(writer);
+            events
+        });
+        let attempt = Attempt {
+            reload: Arc::new(reload),
+            options,
+            use_color,
+            use_stderr_color,
+            bell: Arc::new(Mutex::new(Bell {
+                notify_cmd: options.notify_cmd.clone(),
+                last_rung: None,
+                notifying: Detached::default(),
+            })),
+            sinks: Arc::default(),
+            hooks: Arc::default(),
+            tee,
+            cast,
+            events,
+            filters: LineFilters {
+                strip_sgr: options
+                    .strip_existing
+                    .then(|| Regex::new("\x1b\\[[0-9;:]*m").expect("SGR regex is valid")),
+                neutralize: options.neutralize_escapes,
+                expand_tabs: options.expand_tabs,
+                rewrap_safe: options.rewrap_safe,
+                full_reset: options.full_reset,
+                blank_lines: options.blank_lines,
+            },
+            baseline,
+            seen: Vec::new(),
+            fail_lines: 0,
+            truncated: false,
+            stdout_closed: false,
+            stdin_pump: None,
+        };
+        (attempt, events_writer)
+    }
+
+    /// The exit code after the last run ended with `code`: what stopped it early
+    /// is reported and decides, `stopped` saying what `--max-output` did.
+    /// `--update-baseline` gets its file.
+    fn finish(&self, mut code: i32, stderr_is_colored: bool, stopped: &str) -> i32 {
+        let options = self.options;
+        if self.truncated {
+            let notice = format!(
+                "nscwrs: output reached --max-output ({} bytes), {}",
+                options.max_output.unwrap_or_default(),
+                stopped
+            );
+            if stderr_is_colored {
+                eprintln!("{}", notice.dimmed());
+            } else {
+                eprintln!("{}", notice);
+            }
+            code = TRUNCATED_EXIT_CODE;
+        }
+        if let Some(path) = options
+            .baseline
+            .as_deref()
+            .filter(|_| options.update_baseline)
+        {
+            let content: String = self.seen.iter().map(|line| format!("{}\n", line)).collect();
+            if let Err(err) = fs::write(path, content) {
+                eprintln!("nscwrs: writing {} failed ({})", path.display(), err);
+            }
+        }
+        if self.stdout_closed {
+            code = BROKEN_PIPE_EXIT_CODE;
+        }
+        let signal = STOP_SIGNAL.load(Ordering::Relaxed);
+        if signal != 0 {
+            code = 128 + signal;
+        }
+        code
+    }
+
+    /// Fresh effects for a run, each run has its own verdict
+    fn effects(&self) -> Effects {
+        Effects {
+            bell: Arc::clone(&self.bell),
+            verdict: Arc::default(),
+            sinks: Arc::clone(&self.sinks),
+            hooks: Arc::clone(&self.hooks),
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl Attempt<'_> {
+    fn colorizer(&self, use_color: bool, stream: StreamScope) -> ColorizerMaker {
+        ColorizerMaker {
+            use_color,
+            stream,
+            marker: self.options.marker.clone(),
+            matches: self.events.is_some(),
+            persistent_filters: self.options.persistent_filter,
+        }
+    }
+
+    /// Spawn `command` and color its output until it exits, returning the exit
+    /// code after `fail-on`/`pass-on` rules had their say
+    fn run(&mut self, command: &mut Command, counts: &mut LineCounts) -> i32 {
+        let mut child = command.spawn().expect("Failed to spawn real program");
+        CHILD_PID.store(child.id() as i32, Ordering::Relaxed);
+        if let Some(pump) = &self.stdin_pump {
+            pump.feed(child.stdin.take());
+        }
+        let effects = self.effects();
+        let capacity = self.options.read_buffer.unwrap_or(READ_BUFFER);
+        let stderr_thread = child.stderr.take().map(|stderr| {
+            let colorizer = (
+                self.colorizer(self.use_stderr_color, StreamScope::Stderr),
+                Arc::clone(&self.reload),
+            );
+            spawn_stderr_colorizer(
+                BufReader::with_capacity(capacity, stderr),
+                colorizer,
+                effects.clone(),
+                self.filters.clone(),
+                ContextLines::new(self.options.context, self.use_stderr_color),
+            )
+        });
+
+        let stdout = child.stdout.take().expect("Failed to capture stdout");
+        let (stdout, mut stages) = spawn_stages(&self.options.pipe, stdout);
+        let reader = Segments::new(
+            BufReader::with_capacity(capacity, stdout),
+            self.options.cr_flush,
+        );
+        self.color_stdout(reader, &effects, counts);
+        if self.truncated || self.stdout_closed {
+            // Stages first, the program may be blocked writing to them
+            for stage in &mut stages {
+                let _ = stage.kill();
+            }
+            let _ = child.kill();
+        }
+
+        let status = child.wait().expect("Failed to wait on child process");
+        CHILD_PID.store(0, Ordering::Relaxed);
+        if let Some(thread) = stderr_thread {
+            let _ = thread.join();
+        }
+        // Like `set -o pipefail`: the last stage that failed decides
+        let mut code = exit_code(status);
+        for stage in &mut stages {
+            let stage_code = exit_code(stage.wait().expect("Failed to wait on --pipe stage"));
+            if stage_code != 0 {
+                code = stage_code;
+            }
+        }
+        self.fail_lines = effects.verdict.failed.load(Ordering::Relaxed);
+        effects.verdict.exit_code(code, self.options)
+    }
+
+    /// Color the program's stdout lines onto ours until it ends, `--max-output` is
+    /// reached or our reader goes away
+    fn color_stdout<R: Read + Send + 'static>(
+        &mut self,
+        reader: Segments<R>,
+        effects: &Effects,
+        counts: &mut LineCounts,
+    ) {
+        let options = self.options;
+        let use_color = self.use_color;
+        let maker = self.colorizer(use_color, StreamScope::Stdout);
+        let reload = Arc::clone(&self.reload);
+        let mut generation = reload.generation.load(Ordering::Relaxed);
+        let mut colorizer = maker.make(reload.current());
+        let mut syntax = Syntax::new(options.syntax.as_deref().filter(|_| use_color));
+        let mut lines = StdoutLines::new(
+            options,
+            use_color,
+            &self.filters,
+            (&mut self.tee, &mut self.cast),
+            self.baseline.as_ref(),
+        );
+        // `@final` rules and `--flatten` need everything first: (line, arrived slowly)
+        let mut buffered: Option<Vec<(String, bool, Ending)>> =
+            (colorizer.has_final_rules() || options.flatten).then(Vec::new);
+
+        let segments: Box<dyn Iterator<Item = Option<_>>> = match options.heartbeat {
+            Some(interval) => Box::new(with_heartbeat(reader, interval)),
+            None => Box::new(reader.map(Some)),
+        };
+        let mut last_line_at = Instant::now();
+        let mut quiet_since = Instant::now();
+        // Program output of this run, for `--max-output`
+        let mut read_bytes: u64 = 0;
+        self.truncated = false;
+        for segment in segments {
+            let Some(segment) = segment else {
+                print_heartbeat(quiet_since.elapsed(), use_color);
+                continue;
+            };
+            quiet_since = Instant::now();
+            match segment {
+                Ok((line, ending)) => {
+                    let bytes = (line.len() + ending.as_str().len()) as u64;
+                    counts.lines += 1;
+                    counts.bytes += bytes;
+                    read_bytes += bytes;
+                    let line = self.filters.input(line);
+                    if let Some(rule_set) = reload.newer(&mut generation) {
+                        colorizer = maker.make(rule_set);
+                    }
+                    let mut slow = false;
+                    if let Some(threshold) = options.slow_threshold {
+                        let now = Instant::now();
+                        slow = use_color && now - last_line_at > threshold;
+                        last_line_at = now;
+                    }
+                    match &mut buffered {
+                        Some(_) if ending == Ending::Frame => {}
+                        Some(buffered) => buffered.push((line, slow, ending)),
+                        None => {
+                            let processed = colorizer.process_line(&syntax.apply(&line));
+                            let frame = ending == Ending::Frame;
+                            effects.apply("stdout", &line, &processed, colorizer.rules(), frame);
+                            lines.deliver(&line, processed, slow, ending);
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading line: {}", e);
+                    break;
+                }
+            }
+            if options.max_output.is_some_and(|max| read_bytes >= max) {
+                self.truncated = true;
+                break;
+            }
+            if lines.closed {
+                break;
+            }
+            // With no child to end the output (`--replay`), a stop signal ends it
+            if STOP_SIGNAL.load(Ordering::Relaxed) != 0 && CHILD_PID.load(Ordering::Relaxed) == 0 {
+                break;
+            }
+        }
+
+        if let Some(mut buffered) = buffered {
+            if options.flatten
+                && let Some((_, _, last_ending)) = buffered.last()
+            {
+                // One record, so rules can match across what were line breaks
+                let mut record = String::new();
+                for (idx, (line, _, ending)) in buffered.iter().enumerate() {
+                    record.push_str(line);
+                    if idx + 1 < buffered.len() {
+                        record.push_str(ending.as_str());
+                    }
+                }
+                let slow = buffered.iter().any(|(_, slow, _)| *slow);
+                buffered = vec![(record, slow, *last_ending)];
+            }
+            let highlighted: Vec<String> = buffered
+                .iter()
+                .map(|(line, ..)| syntax.apply(line).into_owned())
+                .collect();
+            let processed = colorizer.process_all(&highlighted);
+            for ((line, slow, ending), processed) in buffered.iter().zip(processed) {
+                effects.apply("stdout", line, &processed, colorizer.rules(), false);
+                lines.deliver(line, processed, *slow, *ending);
+            }
+        }
+        self.stdout_closed = lines.closed;
+        self.seen = lines.finish();
+    }
+}
+
+/// What a colored line sets off besides being shown, on either stream: the bell,
+/// the verdict, `sink:` files, `run:` hooks and `--events-socket`
+#[derive(Clone)]
+struct Effects {
+    bell: Arc<Mutex<Bell>>,
+    verdict: Arc<Verdict>,
+    sinks: Arc<Mutex<Sinks>>,
+    hooks: Arc<Mutex<Hooks>>,
+    events: Option<Events>,
+}
+
+impl Effects {
+    /// `rules` are those of the colorizer that produced `processed`. A `--cr-flush`
+    /// frame only rings and counts, it isn't a line of its own yet.
+    fn apply(
+        &self,
+        stream: &str,
+        line: &str,
+        processed: &ColoredLine,
+        rules: &[Rule],
+        frame: bool,
+    ) {
+        if processed.bell {
+            self.bell.lock().unwrap().ring(line);
+        }
+        self.verdict.record(processed);
+        if frame {
+            return;
+        }
+        if !processed.sinks.is_empty() {
+            self.sinks.lock().unwrap().write(&processed.sinks, line);
+        }
+        if !processed.commands.is_empty() {
+            self.hooks.lock().unwrap().run(&processed.commands, line);
+        }
+        if let Some(events) = &self.events {
+            events.send(stream, line, processed, rules);
+        }
+    }
+}
+
+/// Where the colored stdout lines of a run go: `--baseline`, `--table` and
+/// `--slow-threshold` touch them up, `--context` and `--tail` pick them,
+/// `--collapse` folds them, and they end up on stdout and in the `--tee` and
+/// `--cast` files
+struct StdoutLines<'a> {
+    options: &'a Options,
+    use_color: bool,
+    filters: &'a LineFilters,
+    out: io::StdoutLock<'static>,
+    tee: &'a mut Option<Tee>,
+    cast: &'a mut Option<Cast>,
+    /// Lines of `--baseline`, as the rules see them
+    baseline: Option<&'a HashSet<String>>,
+    /// The lines shown, for `--update-baseline`
+    seen: Vec<String>,
+    table: Option<Table>,
+    context: ContextLines,
+    /// `--tail`: colored as they arrive, so `@when` state stays right, shown at EOF
+    tail: VecDeque<(String, Ending)>,
+    collapser: Collapser,
+    /// The previous line was blank, for `--squeeze-blank`
+    after_blank: bool,
+    /// Writing to stdout failed, the reader went away (`nscwrs prog | head`)
+    closed: bool,
+}
+
+impl<'a> StdoutLines<'a> {
+    fn new(
+        options: &'a Options,
+        use_color: bool,
+        filters: &'a LineFilters,
+        (tee, cast): (&'a mut Option<Tee>, &'a mut Option<Cast>),
+        baseline: Option<&'a HashSet<String>>,
+    ) -> Self {
+        StdoutLines {
+            options,
+            use_color,
+            filters,
+            out: io::stdout().lock(),
+            tee,
+            cast,
+            baseline,
+            seen: Vec::new(),
+            table: (options.table && use_color).then(|| Table::new(&options.table_colors)),
+            context: ContextLines::new(options.context, use_color),
+            tail: VecDeque::new(),
+            collapser: Collapser::default(),
+            after_blank: false,
+            closed: false,
+        }
+    }
+
+    /// The colored `processed` of `line`, `slow` when it came after
+    /// `--slow-threshold` of silence
+    fn deliver(&mut self, line: &str, processed: ColoredLine, slow: bool, ending: Ending) {
+        let options = self.options;
+        let frame = ending == Ending::Frame;
+        // Rules still see blank lines, e.g. to end a `@when` section
+        if !frame && !self.filters.keeps(line, &mut self.after_blank) {
+            return;
+        }
+        if frame && processed.dropped {
+            return;
+        }
+        if options.update_baseline && !frame {
+            self.seen.push(line.to_string());
+        }
+        let new = !frame
+            && self.use_color
+            && self
+                .baseline
+                .is_some_and(|baseline| !baseline.contains(line));
+        let mut colored = processed.text;
+        if let Some(table) = &mut self.table
+            && !frame
+        {
+            colored = table.color(line, colored);
+        }
+        if slow {
+            colored = highlight_line(&colored, options.slow_color.unwrap_or(Color::Red));
+        } else if new {
+            colored = highlight_line(&colored, options.baseline_color.unwrap_or(Color::Green));
+        }
+        colored = self.filters.output(colored);
+        let lines = if frame {
+            vec![(colored, ending)]
+        } else {
+            self.context.push(colored, ending, processed.dropped)
+        };
+        for (colored, ending) in lines {
+            match options.tail {
+                // Progress frames are only worth showing live
+                Some(_) if frame => {}
+                Some(keep) => {
+                    if self.tail.len() == keep {
+                        self.tail.pop_front();
+                    }
+                    self.tail.push_back((colored, ending));
+                }
+                None => self.show(colored, ending),
+            }
+        }
+    }
+
+    /// `--collapse`: collapsed runs always end in a newline, they are no longer
+    /// the program's lines
+    fn show(&mut self, colored: String, ending: Ending) {
+        if ending == Ending::Frame {
+            if let Some(run) = self.collapser.finish(self.use_color) {
+                self.emit(&run, Ending::Lf);
+            }
+            self.emit(&colored, ending);
+        } else if !self.options.collapse {
+            self.emit(&colored, ending);
+        } else if let Some(run) = self.collapser.push(colored, self.use_color) {
+            self.emit(&run, Ending::Lf);
+        }
+    }
+
+    fn emit(&mut self, line: &str, ending: Ending) {
+        if self.closed {
+            return;
+        }
+        if write!(self.out, "{}{}", line, ending.as_str()).is_err() {
+            self.closed = true;
+            return;
+        }
+        if let Some(cast) = self.cast.as_mut()
+            && let Err(err) = cast.write_line(line, ending)
+        {
+            eprintln!("nscwrs: writing {} failed ({})", cast.path.display(), err);
+            *self.cast = None;
+        }
+        if ending == Ending::Frame {
+            // `--cr-flush`: the terminal shows it now, the next one overwrites it
+            if self.out.flush().is_err() {
+                self.closed = true;
+            }
+            return;
+        }
+        if let Some(tee) = self.tee.as_mut()
+            && let Err(err) = tee.write_line(line, ending)
+        {
+            // Keep showing the output, only the copy stops
+            eprintln!("nscwrs: writing {} failed ({})", tee.path.display(), err);
+            *self.tee = None;
+        }
+    }
+
+    /// Show what `--tail` and `--collapse` held back, and return the lines for
+    /// `--update-baseline`
+    fn finish(mut self) -> Vec<String> {
+        for (line, ending) in std::mem::take(&mut self.tail) {
+            self.show(line, ending);
+        }
+        if let Some(run) = self.collapser.finish(self.use_color) {
+            self.emit(&run, Ending::Lf);
+        }
+        self.seen
+    }
+}
+
+/// Flush every file we write and wait for the events writer, before the process
+/// exits without running destructors. The stderr thread and `--pipe` stages were
+/// waited for at the end of each run.
+fn shut_down(attempt: Attempt, events_writer: Option<JoinHandle<()>>) {
+    let _ = io::stdout().flush();
+    if let Some(mut tee) = attempt.tee
+        && let Err(err) = tee.file.flush()
+    {
+        eprintln!("nscwrs: writing {} failed ({})", tee.path.display(), err);
+    }
+    if let Some(mut cast) = attempt.cast
+        && let Err(err) = cast.file.flush()
+    {
+        eprintln!("nscwrs: writing {} failed ({})", cast.path.display(), err);
+    }
+    attempt.sinks.lock().unwrap().flush();
+    // Let the listener have the last events before exiting
+    drop(attempt.events);
+    if let Some(writer) = events_writer {
+        let _ = writer.join();
+    }
+}
+
+/// `--heartbeat`: `items` read on a thread of their own, so that silence can be
+/// noticed. `None` stands for each `interval` that passes without an item.
+fn with_heartbeat<I>(items: I, interval: Duration) -> impl Iterator<Item = Option<I::Item>>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for item in items {
+            if sender.send(item).is_err() {
+                break;
+            }
+        }
+    });
+    std::iter::from_fn(move || match receiver.recv_timeout(interval) {
+        Ok(item) => Some(Some(item)),
+        Err(RecvTimeoutError::Timeout) => Some(None),
+        Err(RecvTimeoutError::Disconnected) => None,
+    })
+}
+
+/// `--heartbeat`: the keepalive line, on stdout where CI systems look for signs
+/// of life. The copy for `--tee` and the `--tail`/`@final` buffers don't get it.
+fn print_heartbeat(quiet: Duration, use_color: bool) {
+    let quiet = match quiet.as_secs() {
+        0 => format!("{}ms", quiet.as_millis()),
+        secs => format!("{}s", secs),
+    };
+    let line = format!("nscwrs: still running, no output for {}", quiet);
+    // Stdout is locked by the caller already; the lock is reentrant
+    let mut out = io::stdout().lock();
+    let _ = if use_color {
+        writeln!(out, "{}", line.dimmed())
+    } else {
+        writeln!(out, "{}", line)
+    };
+    let _ = out.flush();
+}
+
+/// `--slow-threshold`: put the whole line on a `color` background. The line's own
+/// escapes end with a reset, so the background is restored after each of them.
+fn highlight_line(line: &str, color: Color) -> String {
+    let background = format!("\x1b[{}m", color.to_bg_str());
+    let line = line.replace("\x1b[0m", &format!("\x1b[0m{}", background));
+    format!("{}{}\x1b[0m", background, line)
+}
+
+/// `--tee FILE`: a copy of what we print to stdout
+struct Tee {
+    path: PathBuf,
+    file: LineWriter<File>,
+    /// Keep escape sequences in the copy (`--tee-color`)
+    keep_color: bool,
+}
+
+impl Tee {
+    fn create(path: &Path, keep_color: bool) -> io::Result<Self> {
+        Ok(Tee {
+            path: path.to_path_buf(),
+            file: LineWriter::new(File::create(path)?),
+            keep_color,
+        })
+    }
+
+    /// Write one line and its ending, flushed right away so the file can be
+    /// followed live
+    fn write_line(&mut self, line: &str, ending: Ending) -> io::Result<()> {
+        if self.keep_color {
+            write!(self.file, "{}{}", line, ending.as_str())
+        } else {
+            write!(self.file, "{}{}", strip_ansi(line), ending.as_str())
+        }
+    }
+}
+
+/// `--cast FILE`: stdout as an asciinema v2 recording, a JSON header line and then
+/// one `[seconds, "o", text]` event per line, timed from when the file was created
+struct Cast {
+    path: PathBuf,
+    file: LineWriter<File>,
+    started: Instant,
 }
 
-fn get_wrapped_program() -> Option<String> {
-    env::args()
-        .nth(1)
-        .map(|arg| {
-            Path::new(&arg)
-                .file_name()
-                .and_then(|name| name.to_str())
-                .map(|name| name.to_string())
+impl Cast {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut file = LineWriter::new(File::create(path)?);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let header = serde_json::json!({
+            "version": 2,
+            "width": terminal_width().unwrap_or(80),
+            "height": 24,
+            "timestamp": timestamp,
+        });
+        writeln!(file, "{}", header)?;
+        Ok(Cast {
+            path: path.to_path_buf(),
+            file,
+            started: Instant::now(),
         })
-        .flatten()
+    }
+
+    /// Record one line and its ending, with a newline as the terminal gets it (`\r\n`)
+    fn write_line(&mut self, line: &str, ending: Ending) -> io::Result<()> {
+        let ending = match ending {
+            Ending::Lf => "\r\n",
+            ending => ending.as_str(),
+        };
+        let event = serde_json::json!([
+            self.started.elapsed().as_secs_f64(),
+            "o",
+            format!("{}{}", line, ending),
+        ]);
+        writeln!(self.file, "{}", event)
+    }
 }
 
-/// Locate the real program in PATH (excluding wrappers directory)
-fn find_real_program(program: &str) -> Option<PathBuf> {
-    let path_var = env::var("PATH").ok()?;
-    for dir in path_var.split(':') {
-        if dir == WRAP_DIR {
-            continue; // Skip our wrapper directory
+/// `--rewrap-safe COLS`: repeat the escapes in effect at every multiple of `cols`
+/// display columns, so a continuation row that starts there after a soft wrap gets
+/// them again. Resets (`ESC[0m`, `ESC[m`) end what is in effect.
+fn reapply_sgr(line: &str, cols: usize) -> String {
+    if !line.contains('\x1b') {
+        return line.to_string();
+    }
+    let mut result = String::with_capacity(line.len() * 2);
+    let mut active = String::new();
+    let (mut col, mut next_wrap) = (0, cols);
+    let mut rest = line;
+    while let Some(ch) = rest.chars().next() {
+        let params = rest.strip_prefix("\x1b[").and_then(|params| {
+            let end = params.find(|ch: char| !ch.is_ascii_digit() && ch != ';' && ch != ':')?;
+            params[end..].starts_with('m').then(|| &params[..end])
+        });
+        if let Some(params) = params {
+            let escape = &rest[..params.len() + 3];
+            if params.is_empty() || params == "0" {
+                active.clear();
+            } else if params.starts_with("0;") {
+                active = escape.to_string();
+            } else {
+                active.push_str(escape);
+            }
+            result.push_str(escape);
+            rest = &rest[escape.len()..];
+            continue;
         }
-        let candidate = Path::new(dir).join(program);
-        if candidate.exists()
-            && candidate.is_file()
-            && candidate.metadata().ok()?.permissions().mode() & 0o111 != 0
-        {
-            return Some(candidate);
+        let width = ch.width().unwrap_or(0);
+        if width > 0 && col >= next_wrap {
+            result.push_str(&active);
+            while next_wrap <= col {
+                next_wrap += cols;
+            }
         }
+        col += width;
+        result.push(ch);
+        rest = &rest[ch.len_utf8()..];
     }
-    None
+    result
 }
 
-fn parse_color(color: &str) -> Color {
-    match color.to_lowercase().as_str() {
-        "red" => Color::Red,
-        "blue" => Color::Blue,
-        "green" => Color::Green,
-        "yellow" => Color::Yellow,
-        "magenta" => Color::Magenta,
-        "cyan" => Color::Cyan,
-        "white" => Color::White,
-        "black" => Color::Black,
-        "brightred" => Color::BrightRed,
-        "brightblue" => Color::BrightBlue,
-        "brightgreen" => Color::BrightGreen,
-        "brightyellow" => Color::BrightYellow,
-        "brightmagenta" => Color::BrightMagenta,
-        "brightcyan" => Color::BrightCyan,
-        "brightwhite" => Color::BrightWhite,
-        _ => Color::White, // Default to white
-    }
-}
-fn load_color_rules(wrapper_path: &Path) -> Vec<Rule> {
-    let content = fs::read_to_string(wrapper_path).unwrap_or_default();
-    let mut rules = Vec::new();
-    let mut last_fg = None;
-    let mut last_bg = None;
-    let mut awaiting_regex = false;
-
-    for (line_num, line) in content.lines().map(str::trim).enumerate() {
-        if line.is_empty() || line.starts_with('#') {
+/// `text` without ANSI escape sequences (`ESC [ ... final` and `ESC x` pairs)
+fn strip_ansi(text: &str) -> Cow<'_, str> {
+    if !text.contains('\x1b') {
+        return Cow::Borrowed(text);
+    }
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\x1b' {
+            stripped.push(ch);
             continue;
         }
+        // CSI: parameter and intermediate bytes up to a final byte in @..~
+        if chars.next() == Some('[') {
+            for ch in chars.by_ref() {
+                if ('@'..='~').contains(&ch) {
+                    break;
+                }
+            }
+        }
+    }
+    Cow::Owned(stripped)
+}
 
-        if awaiting_regex {
-            match last_fg {
-                Some(fg) => match Regex::new(line) {
-                    Ok(re) => rules.push(Rule {
-                        regex: re,
-                        fg_color: fg,
-                        bg_color: last_bg,
-                    }),
-                    Err(err) => {
-                        eprintln!("Invalid regex on line {}: {} ({})", line_num + 1, line, err)
-                    }
-                },
-                None => eprintln!(
-                    "Regex without preceding color on line {}: {}",
-                    line_num + 1,
-                    line
-                ),
-            }
-            awaiting_regex = false;
-        } else if let Some(color_def) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
-            let (fg, bg) = parse_colors(color_def);
-            if let Some(fg) = fg {
-                last_fg = Some(fg);
-                last_bg = bg;
-                awaiting_regex = true;
-            } else {
-                eprintln!(
-                    "Missing 'fg:' in color definition on line {}: {}",
-                    line_num + 1,
-                    line
-                );
+/// Whether `fail-on`/`pass-on` rules matched during one run, on either stream
+#[derive(Default)]
+struct Verdict {
+    /// Lines that matched a `fail-on` rule
+    failed: AtomicU64,
+    passed: AtomicBool,
+    /// `Severity::exit_code` of the worst `severity:` match, 0 without one
+    severity: AtomicI32,
+}
+
+impl Verdict {
+    fn record(&self, line: &ColoredLine) {
+        if line.fail {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        if line.pass {
+            self.passed.store(true, Ordering::Relaxed);
+        }
+        if let Some(severity) = line.severity {
+            self.severity
+                .fetch_max(severity.exit_code(), Ordering::Relaxed);
+        }
+    }
+
+    /// Our exit code, first rule that applies wins:
+    /// 1. a `fail-on` rule matched: the child's code, or 1 if it succeeded
+    /// 2. a `pass-on` rule matched: 0
+    /// 3. `--exit-on-rules`: 0, the child's code is ignored
+    /// 4. the child's code
+    ///
+    /// `--exit-on-severity` then raises it to the worst `severity:` match's code.
+    /// A run stopped by `--max-output` ends with `TRUNCATED_EXIT_CODE` instead.
+    fn exit_code(&self, child_code: i32, options: &Options) -> i32 {
+        let code = if self.failed.load(Ordering::Relaxed) > 0 {
+            if child_code == 0 { 1 } else { child_code }
+        } else if self.passed.load(Ordering::Relaxed) || options.exit_on_rules {
+            0
+        } else {
+            child_code
+        };
+        if options.exit_on_severity {
+            code.max(self.severity.load(Ordering::Relaxed))
+        } else {
+            code
+        }
+    }
+}
+
+/// Our exit code for a child that ended with `status`. A child killed by a
+/// signal is reported the way shells do, as 128 + the signal number.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+/// How a segment of the program's output ended, so it can be written back the same
+/// way
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ending {
+    Lf,
+    CrLf,
+    /// A lone `\r` with `--cr-flush`: a progress frame
+    Frame,
+    /// The output ended without a newline
+    Eof,
+}
+
+impl Ending {
+    fn as_str(self) -> &'static str {
+        match self {
+            Ending::Lf => "\n",
+            Ending::CrLf => "\r\n",
+            Ending::Frame => "\r",
+            Ending::Eof => "",
+        }
+    }
+}
+
+/// The program's output split into lines, like `BufRead::lines` but telling how
+/// each ended. With `--cr-flush` a carriage return also ends a segment, a progress
+/// frame that can be printed without waiting for the newline; `\r\n` still ends a
+/// plain line.
+struct Segments<R> {
+    reader: BufReader<R>,
+    cr_flush: bool,
+}
+
+impl<R: Read> Segments<R> {
+    fn new(reader: BufReader<R>, cr_flush: bool) -> Self {
+        Segments { reader, cr_flush }
+    }
+
+    /// Bytes up to the next terminator, and the terminator; `None` at EOF
+    fn read_segment(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Ending>> {
+        loop {
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                return Ok((!buf.is_empty()).then_some(Ending::Eof));
             }
+            let end = available
+                .iter()
+                .position(|&byte| byte == b'\n' || (self.cr_flush && byte == b'\r'));
+            let Some(end) = end else {
+                buf.extend_from_slice(available);
+                let used = available.len();
+                self.reader.consume(used);
+                continue;
+            };
+            let terminator = available[end];
+            buf.extend_from_slice(&available[..end]);
+            self.reader.consume(end + 1);
+            if terminator == b'\n' {
+                if buf.last() == Some(&b'\r') {
+                    buf.pop();
+                    return Ok(Some(Ending::CrLf));
+                }
+                return Ok(Some(Ending::Lf));
+            }
+            // Only what has already arrived is checked for a `\n`: waiting for more
+            // would hold the frame back
+            if self.reader.buffer().first() == Some(&b'\n') {
+                self.reader.consume(1);
+                return Ok(Some(Ending::CrLf));
+            }
+            return Ok(Some(Ending::Frame));
         }
     }
+}
+
+impl<R: Read> Iterator for Segments<R> {
+    type Item = io::Result<(String, Ending)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+        let ending = match self.read_segment(&mut buf) {
+            Ok(ending) => ending?,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(
+            String::from_utf8(buf)
+                .map(|line| (line, ending))
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    )
+                }),
+        )
+    }
+}
 
-    rules
+/// Rewriting of every line, on both streams, before and after the rules run
+#[derive(Clone, Default)]
+struct LineFilters {
+    /// The program's own SGR escapes, removed before the rules run (`--strip-existing`)
+    strip_sgr: Option<Regex>,
+    /// Control characters made visible (`--neutralize-escapes`)
+    neutralize: bool,
+    /// Tab stop distance tabs are expanded with (`--expand-tabs`)
+    expand_tabs: Option<usize>,
+    /// Columns between repeats of the active escapes (`--rewrap-safe`)
+    rewrap_safe: Option<usize>,
+    /// End every line holding an escape with a reset (`--full-reset`)
+    full_reset: bool,
+    blank_lines: Option<BlankLines>,
 }
 
-fn parse_colors(color_def: &str) -> (Option<Color>, Option<Color>) {
-    let mut fg = None;
-    let mut bg = None;
+impl LineFilters {
+    /// `line` as the rules get to see it
+    fn input(&self, line: String) -> String {
+        let line = match &self.strip_sgr {
+            Some(sgr) => sgr.replace_all(&line, "").into_owned(),
+            None => line,
+        };
+        let line = if self.neutralize && line.contains(is_control) {
+            neutralize_controls(&line)
+        } else {
+            line
+        };
+        match self.expand_tabs {
+            Some(width) if line.contains('\t') => expand_tabs(&line, width),
+            _ => line,
+        }
+    }
+
+    /// Whether `line` (as returned by `input`) is written, `after_blank` tracking
+    /// whether the last line written was blank
+    fn keeps(&self, line: &str, after_blank: &mut bool) -> bool {
+        let blank = line.trim().is_empty();
+        let keep = self
+            .blank_lines
+            .is_none_or(|blank_lines| blank_lines.keep(blank, *after_blank));
+        if keep {
+            *after_blank = blank;
+        }
+        keep
+    }
 
-    for part in color_def.split(',').map(str::trim) {
-        if let Some(fg_color) = part.strip_prefix("fg:") {
-            fg = Some(parse_color(fg_color));
-        } else if let Some(bg_color) = part.strip_prefix("bg:") {
-            bg = Some(parse_color(bg_color));
+    /// Colored `text` as it is written out
+    fn output(&self, text: String) -> String {
+        let text = match self.rewrap_safe {
+            Some(cols) => reapply_sgr(&text, cols),
+            None => text,
+        };
+        if self.full_reset && text.contains('\x1b') && !text.ends_with("\x1b[0m") {
+            text + "\x1b[0m"
+        } else {
+            text
         }
     }
+}
 
-    (fg, bg)
+/// Control characters other than tab, which `--neutralize-escapes` makes visible
+fn is_control(ch: char) -> bool {
+    ch.is_control() && ch != '\t'
 }
 
-fn apply_color_rules(line: &str, rules: &[Rule], use_color: bool) -> String {
-    if !use_color || rules.is_empty() {
-        return line.to_string();
+/// `--neutralize-escapes`: every control character of the program's output in
+/// caret notation, as `cat -v` shows them, so escapes in untrusted output can't
+/// move the cursor, set the title or recolor the terminal. This happens before
+/// the rules run, so nscwrs's own escapes are never touched. C1 controls are
+/// shown as their 7-bit form (`U+009B` as `^[[`).
+fn neutralize_controls(line: &str) -> String {
+    let mut shown = String::with_capacity(line.len() + 8);
+    for ch in line.chars() {
+        match ch as u32 {
+            _ if !is_control(ch) => shown.push(ch),
+            0x7f => shown.push_str("^?"),
+            code @ 0x80..=0x9f => {
+                shown.push_str("^[");
+                shown.push(char::from(code as u8 - 0x40));
+            }
+            code => {
+                shown.push('^');
+                shown.push(char::from(code as u8 + 0x40));
+            }
+        }
+    }
+    shown
+}
+
+/// `--expand-tabs WIDTH`: tabs replaced by spaces up to the next multiple of
+/// `width` columns, counted in display columns as `cols:` does. Escape sequences
+/// take up no columns.
+fn expand_tabs(line: &str, width: usize) -> String {
+    let mut expanded = String::with_capacity(line.len() + width);
+    let mut col = 0;
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\t' => {
+                let spaces = width - col % width;
+                expanded.extend(std::iter::repeat_n(' ', spaces));
+                col += spaces;
+            }
+            '\x1b' => {
+                expanded.push(ch);
+                // CSI: parameter and intermediate bytes up to a final byte in @..~
+                if let Some(next) = chars.next() {
+                    expanded.push(next);
+                    if next == '[' {
+                        for ch in chars.by_ref() {
+                            expanded.push(ch);
+                            if ('@'..='~').contains(&ch) {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {
+                col += ch.width().unwrap_or(0);
+                expanded.push(ch);
+            }
+        }
     }
+    expanded
+}
 
-    let mut matches: Vec<(usize, usize, usize)> = Vec::new();
+/// Color the child's stderr onto ours. It runs on its own thread so a child
+/// filling one pipe never blocks while we wait on the other.
+fn spawn_stderr_colorizer(
+    stderr: BufReader<ChildStderr>,
+    (maker, reload): (ColorizerMaker, Arc<Reload>),
+    effects: Effects,
+    filters: LineFilters,
+    mut context: ContextLines,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut err = io::stderr();
+        let mut after_blank = false;
+        let mut generation = reload.generation.load(Ordering::Relaxed);
+        let mut colorizer = maker.make(reload.current());
+        for segment in Segments::new(stderr, false) {
+            let Ok((line, ending)) = segment else { break };
+            let line = filters.input(line);
+            if let Some(rule_set) = reload.newer(&mut generation) {
+                colorizer = maker.make(rule_set);
+            }
+            let processed = colorizer.process_line(&line);
+            effects.apply("stderr", &line, &processed, colorizer.rules(), false);
+            if !filters.keeps(&line, &mut after_blank) {
+                continue;
+            }
+            let lines = context.push(filters.output(processed.text), ending, processed.dropped);
+            if lines
+                .iter()
+                .any(|(text, ending)| write!(err, "{}{}", text, ending.as_str()).is_err())
+            {
+                break;
+            }
+        }
+    })
+}
 
-    for (rule_idx, rule) in rules.iter().enumerate() {
-        for cap in rule.regex.captures_iter(line) {
-            if let Some(matched) = cap.get(0) {
-                matches.push((matched.start(), matched.end(), rule_idx));
+/// `--pipe 'sort | uniq -c'`: the stages of a pipeline, each a program and its
+/// arguments. Words are split on whitespace and `'...'` or `"..."` quote a word
+/// that contains spaces or `|`; there are no escapes, variables, globs or
+/// redirections.
+fn parse_pipeline(pipeline: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut stages = vec![Vec::new()];
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for ch in pipeline.chars() {
+        match quote {
+            Some(open) if ch == open => quote = None,
+            Some(_) => word.get_or_insert_default().push(ch),
+            None if ch == '\'' || ch == '"' => {
+                quote = Some(ch);
+                word.get_or_insert_default();
             }
+            None if ch == '|' || ch.is_whitespace() => {
+                let stage = stages.last_mut().expect("there is always a stage");
+                stage.extend(word.take());
+                if ch == '|' {
+                    stages.push(Vec::new());
+                }
+            }
+            None => word.get_or_insert_default().push(ch),
         }
     }
+    if let Some(open) = quote {
+        return Err(format!("unclosed {}", open));
+    }
+    stages
+        .last_mut()
+        .expect("there is always a stage")
+        .extend(word);
+    if stages.iter().any(Vec::is_empty) {
+        return Err("empty stage".to_string());
+    }
+    Ok(stages)
+}
 
-    if matches.is_empty() {
-        return line.to_string();
+/// `--syntax LANG`: stdout lines highlighted as `lang` before the rules see them,
+/// with the `syntax` feature
+struct Syntax {
+    #[cfg(feature = "syntax")]
+    highlighter: Option<nscwrs::SyntaxHighlighter>,
+}
+
+impl Syntax {
+    fn new(lang: Option<&str>) -> Self {
+        #[cfg(feature = "syntax")]
+        return Syntax {
+            highlighter: lang.and_then(nscwrs::SyntaxHighlighter::new),
+        };
+        #[cfg(not(feature = "syntax"))]
+        {
+            let _ = lang;
+            Syntax {}
+        }
+    }
+
+    /// Why `lang` can't be highlighted, if it can't
+    fn unavailable(lang: &str) -> Option<&'static str> {
+        #[cfg(feature = "syntax")]
+        return nscwrs::SyntaxHighlighter::new(lang)
+            .is_none()
+            .then_some("unknown language");
+        #[cfg(not(feature = "syntax"))]
+        {
+            let _ = lang;
+            Some("nscwrs was built without the syntax feature")
+        }
+    }
+
+    fn apply<'a>(&mut self, line: &'a str) -> Cow<'a, str> {
+        #[cfg(feature = "syntax")]
+        if let Some(highlighter) = &mut self.highlighter {
+            return Cow::Owned(highlighter.highlight(line));
+        }
+        Cow::Borrowed(line)
     }
+}
+
+/// `--pipe`: start every stage with the previous one's output as its input, and
+/// return the last stage's output along with the stages to wait for
+fn spawn_stages(pipe: &[Vec<String>], mut stdout: ChildStdout) -> (ChildStdout, Vec<Child>) {
+    let mut stages = Vec::new();
+    for stage in pipe {
+        let mut child = Command::new(&stage[0])
+            .args(&stage[1..])
+            .stdin(Stdio::from(stdout))
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to spawn --pipe stage '{}' ({})", stage[0], err);
+                std::process::exit(1);
+            });
+        stdout = child.stdout.take().expect("Failed to capture stdout");
+        stages.push(child);
+    }
+    (stdout, stages)
+}
+
+/// Summary printed by `--timing`
+fn report_timing(lines: u64, bytes: u64, started: Instant) {
+    let elapsed = started.elapsed().as_secs_f64();
+    let per_sec = |count: u64| {
+        if elapsed > 0.0 {
+            count as f64 / elapsed
+        } else {
+            0.0
+        }
+    };
+    eprintln!(
+        "nscwrs: {} lines, {} bytes in {:.3}s ({:.0} lines/s, {:.2} MiB/s)",
+        lines,
+        bytes,
+        elapsed,
+        per_sec(lines),
+        per_sec(bytes) / (1024.0 * 1024.0)
+    );
+}
+
+/// `--notify`: what the notification says, e.g. `make failed (exit 2, 3 fail-on
+/// lines) after 41.2s`
+fn notification_text(program: &str, code: i32, fail_lines: u64, started: Instant) -> String {
+    let outcome = if code == 0 { "finished" } else { "failed" };
+    let mut details = format!("exit {}", code);
+    if fail_lines > 0 {
+        let plural = if fail_lines == 1 { "" } else { "s" };
+        details.push_str(&format!(", {} fail-on line{}", fail_lines, plural));
+    }
+    format!(
+        "{} {} ({}) after {:.1}s",
+        program,
+        outcome,
+        details,
+        started.elapsed().as_secs_f64()
+    )
+}
+
+/// `--notify`: a desktop notification through the terminal, OSC 777 for VTE,
+/// urxvt and foot, OSC 9 for iTerm2, WezTerm and Windows Terminal. Terminals that
+/// understand neither (or can't be told apart) get the plain bell.
+fn notify_desktop(summary: &str) {
+    if atty::is(Stream::Stderr) {
+        eprint!("{}", notification(summary, |key| env::var(key).ok()));
+    }
+}
+
+/// The escape sequence `notify_desktop` sends for `summary` to the terminal that
+/// `env` describes
+fn notification(summary: &str, env: impl Fn(&str) -> Option<String>) -> String {
+    let var = |name: &str| env(name).unwrap_or_default();
+    let term = var("TERM");
+    // The terminator is BEL, so the text itself must not contain one or ESC
+    let summary: String = summary.chars().filter(|ch| !ch.is_control()).collect();
+    if !var("VTE_VERSION").is_empty() || term.starts_with("rxvt") || term.starts_with("foot") {
+        format!("\x1b]777;notify;nscwrs;{}\x07", summary.replace(';', ","))
+    } else if matches!(var("TERM_PROGRAM").as_str(), "iTerm.app" | "WezTerm")
+        || !var("WT_SESSION").is_empty()
+    {
+        format!("\x1b]9;{}\x07", summary)
+    } else {
+        "\x07".to_string()
+    }
+}
 
-    matches.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| (b.1 - b.0).cmp(&(a.1 - a.0))));
+/// `--color-test`: every color token a rule file can use, each shown as foreground
+/// text and as a background swatch. Stops quietly when stdout is closed (`| head`).
+fn color_test() -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    let swatch = |token: &str| {
+        // Parsed like a rule file would, so what is shown is what `fg:` gets
+        let color = parse_color_token(token).expect("color test tokens are valid");
+        format!(
+            "{} {}",
+            format!("{:<16}", format!("fg:{}", token)).color(color),
+            "  ".on_color(color)
+        )
+    };
+    let mut print_rows = |title: &str, tokens: Vec<String>, per_row: usize| {
+        writeln!(out, "{}", title)?;
+        for row in tokens.chunks(per_row) {
+            let row: Vec<String> = row.iter().map(|token| swatch(token)).collect();
+            writeln!(out, "{}", row.join("  "))?;
+        }
+        Ok::<_, io::Error>(())
+    };
 
-    let mut filtered_matches = Vec::new();
-    let mut last_end = 0;
+    let named = NAMES
+        .iter()
+        .map(|name| name.to_string())
+        .chain(NAMES.iter().map(|name| format!("bright{}", name)));
+    print_rows("16 named colors:", named.collect(), 4)?;
+    let palette = (16..=255).map(|index: u16| index.to_string());
+    print_rows(
+        "\n256-color palette (0-15 are the named colors):",
+        palette.collect(),
+        6,
+    )?;
+    let gradient = (0..24).map(|step| {
+        // Around the hue circle: red, yellow, green, cyan, blue, magenta
+        let (sector, offset) = (step / 4, (step % 4) * 255 / 4);
+        let (r, g, b) = match sector {
+            0 => (255, offset, 0),
+            1 => (255 - offset, 255, 0),
+            2 => (0, 255, offset),
+            3 => (0, 255 - offset, 255),
+            4 => (offset, 0, 255),
+            _ => (255, 0, 255 - offset),
+        };
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    });
+    print_rows("\nTruecolor gradient:", gradient.collect(), 6)
+}
 
-    for m in matches {
-        if m.0 >= last_end {
-            filtered_matches.push(m);
-            last_end = m.1;
+/// `--selftest`: rules, a program and the colorizer end to end, the way a wrapper
+/// uses them, with PASS or FAIL for each step. True when all passed.
+fn selftest() -> bool {
+    const RULES: &str = "[fg:red] ^ERROR\ngreen: \\bok\\b\n";
+    const EXPECTED: &str = "\x1b[31mERROR\x1b[0m is \x1b[32mok\x1b[0m";
+    fn report<T>(step: &str, result: Result<T, String>) -> Option<T> {
+        match &result {
+            Ok(_) => println!("PASS: {}", step),
+            Err(problem) => println!("FAIL: {} ({})", step, problem),
         }
+        result.ok()
     }
 
-    let mut result = String::with_capacity(line.len() * 2);
-    let mut last_pos = 0;
+    let rule_set = parse_color_rules(RULES, Path::new("."));
+    let parsed = match rule_set.diagnostics.first() {
+        None if rule_set.rules().len() == 2 => Ok(()),
+        None => Err(format!("{} rules instead of 2", rule_set.rules().len())),
+        Some(diagnostic) => Err(diagnostic.to_string()),
+    };
+    if report("parse the rules", parsed).is_none() {
+        return false;
+    }
 
-    for (start, end, rule_idx) in filtered_matches {
-        if start > last_pos {
-            result.push_str(&line[last_pos..start]);
+    let output = shell_command("echo ERROR is ok")
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output();
+    let ran = match output {
+        Ok(output) if output.status.success() => {
+            let text = String::from_utf8_lossy(&output.stdout);
+            match text.lines().next().unwrap_or_default() {
+                line @ "ERROR is ok" => Ok(line.to_string()),
+                other => Err(format!("printed {:?}", other)),
+            }
         }
+        Ok(output) => Err(format!("exited with {}", exit_code(output.status))),
+        Err(err) => Err(err.to_string()),
+    };
+    let Some(line) = report("run echo", ran) else {
+        return false;
+    };
+
+    colored::control::set_override(true);
+    let colored = Colorizer::new(rule_set, true).colorize_line(&line);
+    let matched = match colored.as_str() {
+        EXPECTED => Ok(()),
+        other => Err(format!("got {:?}", other)),
+    };
+    report("color the output", matched).is_some()
+}
+
+/// `--dump-rules FILE`: show how the loader read `path`, problems included
+fn dump_rules(path: &Path) {
+    if !path.is_file() {
+        eprintln!("Rule file not found: {:?}", path);
+        std::process::exit(1);
+    }
+    let rule_set = load_color_rules(path);
+    print!("{}", rule_set);
+    for diagnostic in &rule_set.diagnostics {
+        println!("{}", diagnostic);
+    }
+}
+
+/// `--explain LINE --rules FILE`: every match the file's rules find on `LINE`,
+/// whether it is shown or which match it lost to, and the colored line
+fn explain_line(line: &str, options: &Options) {
+    let Some(path) = &options.rules else {
+        eprintln!("--explain needs the rule file to use, given with --rules FILE");
+        std::process::exit(2);
+    };
+    if !path.is_file() {
+        eprintln!("Rule file not found: {:?}", path);
+        std::process::exit(1);
+    }
+    let rule_set = load_color_rules(path);
+    print_diagnostics(path, &rule_set, options);
+    let use_color = should_colorize(Stream::Stdout, options.color, |key| env::var(key).ok());
+    colored::control::set_override(use_color);
+    let colorizer = Colorizer::new(rule_set, use_color).with_matches();
+    let candidates = colorizer.candidates(line);
+    let processed = colorizer.process_line(line);
+    let rules = colorizer.rules();
+    if candidates.is_empty() {
+        println!("no rule matched");
+    }
+    for found in &candidates {
+        let rule = &rules[found.rule];
+        let outcome = if processed.matches.contains(found) {
+            "shown".to_string()
+        } else if !rule.styles() {
+            "no color, acts on the line only".to_string()
+        } else if let Some(winner) = processed
+            .matches
+            .iter()
+            .find(|shown| shown.start < found.end && found.start < shown.end)
+        {
+            format!(
+                "lost to rule {} (line {})",
+                winner.rule,
+                rules[winner.rule].line()
+            )
+        } else {
+            "not shown".to_string()
+        };
+        println!(
+            "rule {} (line {}): {} matched {}..{} {:?}, {}",
+            found.rule,
+            rule.line(),
+            rule,
+            found.start,
+            found.end,
+            line.get(found.start..found.end).unwrap_or_default(),
+            outcome
+        );
+    }
+    println!("result: {}", processed.text);
+}
+
+/// `--profile-rules FILE < SAMPLE`: run each rule of the file on its own over
+/// every line of the sample and list the rules slowest first, with how often
+/// their regex matched
+fn profile_rules(path: &Path, options: &Options) -> io::Result<()> {
+    if !path.is_file() {
+        eprintln!("Rule file not found: {:?}", path);
+        std::process::exit(1);
+    }
+    let rule_set = load_color_rules(path);
+    print_diagnostics(path, &rule_set, options);
+    let lines = io::stdin()
+        .lock()
+        .lines()
+        .collect::<io::Result<Vec<String>>>()?;
+
+    let mut report: Vec<(Duration, usize, &Rule)> = rule_set
+        .rules()
+        .iter()
+        .map(|rule| {
+            let start = Instant::now();
+            let matches = lines.iter().map(|line| rule.count_matches(line)).sum();
+            (start.elapsed(), matches, rule)
+        })
+        .collect();
+    report.sort_by_key(|(elapsed, ..)| std::cmp::Reverse(*elapsed));
 
-        let rule = &rules[rule_idx];
-        let segment = &line[start..end];
-        let mut styled = segment.color(rule.fg_color);
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{} rules over {} lines", report.len(), lines.len())?;
+    for (elapsed, matches, rule) in report {
+        writeln!(
+            stdout,
+            "{:>10.3}ms {:>8} matches  line {}: {}",
+            elapsed.as_secs_f64() * 1000.0,
+            matches,
+            rule.line(),
+            rule.pattern()
+        )?;
+    }
+    Ok(())
+}
 
-        if let Some(bg) = rule.bg_color {
-            styled = styled.on_color(bg);
+/// Color `input` to stdout as it is read, or all at once for `@final` rules
+/// Line endings are written back as they were read.
+fn color_input(
+    colorizer: &Colorizer,
+    input: impl Read,
+    mut context: ContextLines,
+) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let segments = Segments::new(BufReader::new(input), false);
+    let mut write = |line: ColoredLine, ending: Ending| {
+        for (text, ending) in context.push(line.text, ending, line.dropped) {
+            write!(stdout, "{}{}", text, ending.as_str())?;
+        }
+        io::Result::Ok(())
+    };
+    if colorizer.has_final_rules() {
+        let (lines, endings): (Vec<String>, Vec<Ending>) = segments
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        for (line, ending) in colorizer.process_all(&lines).into_iter().zip(endings) {
+            write(line, ending)?;
         }
+        return Ok(());
+    }
+    for segment in segments {
+        let (line, ending) = segment?;
+        write(colorizer.process_line(&line), ending)?;
+    }
+    Ok(())
+}
+
+/// `--diff`: color stdin as a unified diff, with no program to run
+fn color_diff(use_color: bool) -> io::Result<()> {
+    let colorizer = Colorizer::new(parse_color_rules(DIFF_RULES, Path::new(".")), use_color);
+    color_input(&colorizer, io::stdin(), ContextLines::new(0, use_color))
+}
 
-        result.push_str(&styled.to_string());
-        last_pos = end;
+/// `--replay LOG --rules FILE`: color a log captured earlier with the current
+/// rules, as if the program were printing it now. The log takes the way of a live
+/// run's stdout; returns the exit code.
+fn replay(log: &Path, options: &Options) -> i32 {
+    let Some(rules) = &options.rules else {
+        eprintln!("--replay needs the rule file to use, given with --rules FILE");
+        std::process::exit(2);
+    };
+    if !options.pipe.is_empty() {
+        eprintln!("--pipe needs a program to run, it can't be used with --replay");
+        std::process::exit(2);
+    }
+    if !rules.is_file() {
+        eprintln!("Rule file not found: {:?}", rules);
+        std::process::exit(1);
     }
+    let input = match File::open(log) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("Could not open {} ({})", log.display(), err);
+            std::process::exit(1);
+        }
+    };
+    let sources = RuleSources {
+        // Rule files are named after their program, for `prog:` rules
+        program: rules
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        args: Vec::new(),
+        wrapper_path: rules.clone(),
+        embedded: None,
+        subcommand_path: None,
+        ext_path: None,
+        system_path: None,
+        global_path: None,
+        env_rules: Vec::new(),
+        accent: None,
+    };
+    let rule_set = sources.load(options, report_diagnostics);
+    let use_color = should_colorize(Stream::Stdout, options.color, |key| env::var(key).ok());
+    let stderr_is_colored =
+        should_colorize(Stream::Stderr, options.color, |key| env::var(key).ok());
+    colored::control::set_override(use_color);
+    let reload = Reload {
+        sources,
+        options: options.clone(),
+        rule_set: Mutex::new(rule_set),
+        generation: AtomicUsize::new(0),
+    };
+    watch_sighup();
+    watch_stop_signals();
+    let (mut attempt, events_writer) = Attempt::new(options, reload, use_color, false);
+    let capacity = options.read_buffer.unwrap_or(READ_BUFFER);
+    let reader = Segments::new(BufReader::with_capacity(capacity, input), options.cr_flush);
+    let effects = attempt.effects();
+    attempt.color_stdout(reader, &effects, &mut LineCounts::default());
+    let code = effects.verdict.exit_code(0, options);
+    let code = attempt.finish(code, stderr_is_colored, "replay stopped");
+    shut_down(attempt, events_writer);
+    code
+}
 
-    if last_pos < line.len() {
-        result.push_str(&line[last_pos..]);
+/// `--check FILE...`: load every file as a rule file and report its problems, for
+/// validating rule files in CI. Nothing is run. True when all are clean.
+fn check_rules(paths: &[String], options: &Options) -> bool {
+    let mut clean = true;
+    for path in paths.iter().map(Path::new) {
+        if !path.is_file() {
+            eprintln!("{}: rule file not found", path.display());
+            clean = false;
+            continue;
+        }
+        let rule_set = load_color_rules(path);
+        clean &= rule_set.diagnostics.is_empty();
+        print_diagnostics(path, &rule_set, options);
     }
+    clean
+}
 
-    result
+/// `--new PROGRAM`: write `<wrap_dir>/PROGRAM` from the template, with a shebang
+/// pointing at this executable
+fn create_wrapper(program: &str, force: bool) {
+    let path = Path::new(&resolve_wrap_dir()).join(program);
+    if path.exists() && !force {
+        eprintln!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        );
+        std::process::exit(1);
+    }
+    let nscwrs = env::current_exe().unwrap_or_else(|_| PathBuf::from("nscwrs"));
+    let content = format!("#!{}\n{}", nscwrs.display(), WRAPPER_TEMPLATE);
+    if let Err(err) = fs::write(&path, content) {
+        eprintln!("Could not write {} ({})", path.display(), err);
+        std::process::exit(1);
+    }
+    #[cfg(unix)]
+    if let Err(err) = fs::set_permissions(&path, fs::Permissions::from_mode(0o755)) {
+        eprintln!("Could not make {} executable ({})", path.display(), err);
+    }
+    println!("Created {}", path.display());
 }
+
 fn main() {
-    let wrapped_program = get_wrapped_program().expect("Failed to determine wrapped program");
-    let wrapper_path = Path::new(WRAP_DIR).join(&wrapped_program);
-    if !wrapper_path.exists() {
+    let mut argv = env::args();
+    let argv0 = argv.next().unwrap_or_default();
+    let (mut options, args) = match multicall_name(&argv0) {
+        Some(program) => (
+            Options::default(),
+            std::iter::once(program).chain(argv).collect(),
+        ),
+        None => parse_args(argv.collect()),
+    };
+    if let Some(limit) = options.regex_size_limit {
+        set_regex_size_limit(limit);
+    }
+    if let Some(program) = &options.new {
+        create_wrapper(program, options.force);
+        return;
+    }
+    if options.color_test {
+        let use_color = should_colorize(Stream::Stdout, options.color, |key| env::var(key).ok());
+        colored::control::set_override(use_color);
+        let _ = color_test();
+        return;
+    }
+    if options.diff {
+        let use_color = should_colorize(Stream::Stdout, options.color, |key| env::var(key).ok());
+        colored::control::set_override(use_color);
+        let _ = color_diff(use_color);
+        return;
+    }
+    if let Some(log) = &options.replay {
+        std::process::exit(replay(log, &options));
+    }
+    if let Some(line) = &options.explain {
+        explain_line(line, &options);
+        return;
+    }
+    if options.check {
+        if args.is_empty() {
+            eprintln!("Missing rule file for --check");
+            std::process::exit(2);
+        }
+        std::process::exit(if check_rules(&args, &options) { 0 } else { 1 });
+    }
+    if options.selftest {
+        std::process::exit(if selftest() { 0 } else { 1 });
+    }
+    if options.profile_rules {
+        let Some(path) = args.first() else {
+            eprintln!("Missing rule file for --profile-rules");
+            std::process::exit(2);
+        };
+        let _ = profile_rules(Path::new(path), &options);
+        return;
+    }
+    if options.dump_rules {
+        let Some(path) = args.first() else {
+            eprintln!("Missing rule file for --dump-rules");
+            std::process::exit(2);
+        };
+        dump_rules(Path::new(path));
+        return;
+    }
+    let wrapped_program = get_wrapped_program(&args).expect("Failed to determine wrapped program");
+    let accent = options.auto_accent.then(|| accent_color(&wrapped_program));
+    let wrap_dir = resolve_wrap_dir();
+    let profile = options
+        .profile
+        .clone()
+        .or_else(|| env::var("NSCWRS_PROFILE").ok().filter(|p| !p.is_empty()));
+    let wrapper_path = options
+        .rules
+        .clone()
+        .unwrap_or_else(|| find_rule_file(&wrap_dir, profile.as_deref(), &wrapped_program));
+    let system_path =
+        system_rule_file(&wrapped_program).filter(|path| !options.no_system && path.is_file());
+    let global_path = Some(Path::new(&wrap_dir).join(GLOBAL_RULES))
+        .filter(|path| !options.no_global && path.is_file());
+    let subcommand_path = find_subcommand_rule_file(&wrapper_path, &args[1..]);
+    let ext_path = options
+        .by_ext
+        .then(|| find_ext_rule_file(&wrapper_path, &wrapped_program, &args[1..]))
+        .flatten();
+    // `pattern=color;pattern=color`, the `--highlight` format, for setups without files
+    let env_rules = env::var("NSCWRS_RULES").unwrap_or_default();
+    let env_rules: Vec<&str> = env_rules
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    // Compiled-in rules stand in for a missing rule file
+    let embedded = EMBEDDED_RULES
+        .iter()
+        .find(|(program, _)| *program == wrapped_program)
+        .map(|(_, content)| *content)
+        .filter(|_| !wrapper_path.exists());
+    if !wrapper_path.exists()
+        && embedded.is_none()
+        && system_path.is_none()
+        && ext_path.is_none()
+        && subcommand_path.is_none()
+        && env_rules.is_empty()
+        && options.highlights.is_empty()
+        && !options.logfmt
+    {
         eprintln!("Wrapper script not found: {:?}", wrapper_path);
         std::process::exit(1);
     }
 
-    let real_program = find_real_program(&wrapped_program).unwrap_or_else(|| {
-        eprintln!("Could not find real program for '{}'", wrapped_program);
+    if let Some(lang) = options.syntax.clone()
+        && let Some(reason) = Syntax::unavailable(&lang)
+    {
+        eprintln!(
+            "nscwrs: --syntax {}: {}, only the rules apply",
+            lang, reason
+        );
+        options.syntax = None;
+    }
+
+    let sources = RuleSources {
+        program: wrapped_program.clone(),
+        args: args[1..].to_vec(),
+        wrapper_path,
+        embedded,
+        subcommand_path,
+        ext_path,
+        system_path,
+        global_path,
+        env_rules: env_rules.iter().map(|entry| entry.to_string()).collect(),
+        accent,
+    };
+    let report = if options.verify {
+        report_verified
+    } else {
+        report_diagnostics
+    };
+    let rule_set = sources.load(&options, report);
+
+    // `@exec` decouples the wrapper name from the binary that gets run
+    let target = rule_set.exec.as_deref().unwrap_or(&wrapped_program);
+    let mut passed_over = Vec::new();
+    let real_program = if target.contains('/') {
+        Some(PathBuf::from(target))
+    } else {
+        if options.search_wrapper_dir {
+            eprintln!(
+                "Warning: --search-wrapper-dir also looks for '{}' in {}. Only nscwrs itself \
+                 and its wrappers are refused there; anything else that runs this wrapper \
+                 again will recurse.",
+                target, wrap_dir
+            );
+        }
+        find_real_program(
+            target,
+            &wrap_dir,
+            options.search_wrapper_dir,
+            &mut passed_over,
+        )
+    };
+    if options.verify {
+        let found = report_program(target, real_program.as_deref(), &passed_over);
+        let clean = found && rule_set.diagnostics.is_empty();
+        std::process::exit(if clean { 0 } else { 1 });
+    }
+    let real_program = real_program.unwrap_or_else(|| {
+        eprintln!("Could not find real program for '{}'", target);
         std::process::exit(1);
     });
 
-    let rules = load_color_rules(&wrapper_path);
+    // Stderr is left alone unless asked for, or the rules target it explicitly.
+    // Secrets are masked there regardless, only without color.
+    let color_stderr = options.stderr || rule_set.scopes_stderr();
+    let mut command = Command::new(real_program);
+    command.args(&args[1..]).stdout(Stdio::piped()); // Skipping the wrapper name
+    // A terminal stays the program's own, for interactive programs and job control;
+    // other input is pumped to it by `StdinPump`
+    let pump_stdin = !atty::is(Stream::Stdin);
+    command.stdin(if pump_stdin {
+        Stdio::piped()
+    } else {
+        Stdio::inherit()
+    });
+    command.envs(
+        rule_set
+            .env
+            .iter()
+            .chain(&options.setenv)
+            .map(|(k, v)| (k, v)),
+    );
+    if color_stderr || rule_set.redacts_stderr() {
+        command.stderr(Stdio::piped());
+    }
+
+    let use_color = should_colorize(Stream::Stdout, options.color, |key| env::var(key).ok());
+    let stderr_is_colored =
+        should_colorize(Stream::Stderr, options.color, |key| env::var(key).ok());
+    let use_stderr_color = color_stderr && stderr_is_colored;
+    // `colored` does its own (stdout) terminal check; each colorizer decides for
+    // its own stream, so only keep `colored` from getting in the way
+    colored::control::set_override(use_color || stderr_is_colored);
+
+    if !options.persistent_filter
+        && (use_color || use_stderr_color)
+        && rule_set
+            .rules()
+            .iter()
+            .any(|rule| rule.filter_cmd().is_some())
+    {
+        eprintln!(
+            "Warning: filter-cmd: starts its command for every match; --persistent-filter \
+             keeps one running"
+        );
+    }
+
+    let reload = Reload {
+        sources,
+        options: options.clone(),
+        rule_set: Mutex::new(rule_set),
+        generation: AtomicUsize::new(0),
+    };
+    watch_sighup();
+    watch_stop_signals();
+    let (mut attempt, events_writer) = Attempt::new(&options, reload, use_color, use_stderr_color);
+    attempt.stdin_pump = pump_stdin.then(StdinPump::start);
+    let started = Instant::now();
+    let mut counts = LineCounts::default();
+    let mut code = attempt.run(&mut command, &mut counts);
+    for retry in 1..=options.retries {
+        if code == 0 || attempt.truncated || attempt.stdout_closed {
+            break;
+        }
+        if STOP_SIGNAL.load(Ordering::Relaxed) != 0 {
+            break;
+        }
+        let separator = format!("nscwrs: retry {}/{}", retry, options.retries);
+        if let Some(accent) = accent.filter(|_| stderr_is_colored) {
+            eprintln!("{}", separator.color(accent));
+        } else if stderr_is_colored {
+            eprintln!("{}", separator.dimmed());
+        } else {
+            eprintln!("{}", separator);
+        }
+        code = attempt.run(&mut command, &mut counts);
+    }
+    let code = attempt.finish(code, stderr_is_colored, "program stopped");
+    let fail_lines = attempt.fail_lines;
+    shut_down(attempt, events_writer);
 
-    let args: Vec<String> = env::args().skip(2).collect(); // Skipping the wrapper name and program
+    if options.timing {
+        report_timing(counts.lines, counts.bytes, started);
+    }
+    if options.notify {
+        let summary = notification_text(&wrapped_program, code, fail_lines, started);
+        notify_desktop(&summary);
+    }
+    std::process::exit(code);
+}
 
-    let mut child = Command::new(real_program)
-        .args(&args)
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn real program");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let reader = BufReader::new(stdout);
-    let stdout_handle = io::stdout();
-    let mut out = stdout_handle.lock();
-    let use_color = atty::is(Stream::Stdout);
+    #[cfg(windows)]
+    #[test]
+    fn names_with_an_extension_are_tried_as_given_first() {
+        let candidates = executable_candidates(Path::new("bin"), "tool.exe");
+        assert_eq!(candidates[0], Path::new("bin").join("tool.exe"));
+        assert!(candidates.len() > 1);
+    }
 
-    for line in reader.lines() {
-        match line {
-            Ok(line) => {
-                let colored = apply_color_rules(&line, &rules, use_color);
-                writeln!(out, "{}", colored).unwrap();
-            }
-            Err(e) => {
-                eprintln!("Error reading line from child process: {}", e);
-                break;
+    #[cfg(unix)]
+    #[test]
+    fn names_are_tried_as_given() {
+        assert_eq!(
+            executable_candidates(Path::new("bin"), "tool"),
+            [Path::new("bin").join("tool")]
+        );
+    }
+
+    #[test]
+    fn light_backgrounds_get_a_black_fallback() {
+        assert_eq!(background_default_color(Some("0;15")), Some(Color::Black));
+        assert_eq!(
+            background_default_color(Some("0;default;7")),
+            Some(Color::Black)
+        );
+        assert_eq!(background_default_color(Some("15;0")), None);
+        assert_eq!(background_default_color(Some("15;8")), None);
+        assert_eq!(background_default_color(None), None);
+    }
+
+    #[test]
+    fn accent_colors_depend_only_on_the_name() {
+        assert_eq!(accent_color("make"), accent_color("make"));
+        let names = [
+            "make", "cargo", "git", "npm", "docker", "kubectl", "pytest", "go",
+        ];
+        let accents: HashSet<String> = names
+            .iter()
+            .map(|name| format!("{:?}", accent_color(name)))
+            .collect();
+        assert!(accents.len() > 1);
+        assert!(
+            names
+                .iter()
+                .all(|name| ACCENT_COLORS.contains(&accent_color(name)))
+        );
+    }
+
+    #[test]
+    fn notification_text_tells_the_outcome() {
+        let started = Instant::now();
+        let text = notification_text("make", 2, 3, started);
+        assert!(text.starts_with("make failed (exit 2, 3 fail-on lines) after "));
+        assert!(text.ends_with('s'));
+        let text = notification_text("make", 0, 1, started);
+        assert!(text.starts_with("make finished (exit 0, 1 fail-on line) after "));
+        assert!(
+            notification_text("make", 0, 0, started).starts_with("make finished (exit 0) after ")
+        );
+    }
+
+    #[test]
+    fn notifications_use_the_terminals_escape() {
+        let terminal = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
             }
+        };
+        assert_eq!(
+            notification("a; b\x1b", terminal(&[("VTE_VERSION", "7600")])),
+            "\x1b]777;notify;nscwrs;a, b\x07"
+        );
+        assert_eq!(
+            notification("done", terminal(&[("TERM", "foot")])),
+            "\x1b]777;notify;nscwrs;done\x07"
+        );
+        assert_eq!(
+            notification("a; b", terminal(&[("TERM_PROGRAM", "WezTerm")])),
+            "\x1b]9;a; b\x07"
+        );
+        assert_eq!(
+            notification("done", terminal(&[("WT_SESSION", "1")])),
+            "\x1b]9;done\x07"
+        );
+        assert_eq!(notification("done", terminal(&[("TERM", "xterm")])), "\x07");
+    }
+
+    #[test]
+    fn active_escapes_are_repeated_at_every_wrap_column() {
+        assert_eq!(
+            reapply_sgr("ab\x1b[41mcdefgh\x1b[0mij", 3),
+            "ab\x1b[41mc\x1b[41mdef\x1b[41mgh\x1b[0mij"
+        );
+        // Escapes add up until a reset, and wide characters take two columns
+        assert_eq!(
+            reapply_sgr("\x1b[1m\x1b[31m漢字\x1b[0m", 2),
+            "\x1b[1m\x1b[31m漢\x1b[1m\x1b[31m字\x1b[0m"
+        );
+        assert_eq!(reapply_sgr("plain text", 2), "plain text");
+    }
+
+    #[test]
+    fn pipelines_split_into_stages_and_quoted_words() {
+        assert_eq!(
+            parse_pipeline("sort  -r | grep 'a | b' \"c d\"|wc").unwrap(),
+            [vec!["sort", "-r"], vec!["grep", "a | b", "c d"], vec!["wc"]]
+        );
+        assert_eq!(parse_pipeline("grep ''").unwrap(), [vec!["grep", ""]]);
+        assert_eq!(parse_pipeline("sort | 'uniq").unwrap_err(), "unclosed '");
+        assert_eq!(parse_pipeline("sort || uniq").unwrap_err(), "empty stage");
+    }
+
+    #[test]
+    fn default_color_takes_any_rule_color() {
+        let (options, _) = parse_args(
+            ["--default-color", "214", "prog"]
+                .map(String::from)
+                .to_vec(),
+        );
+        assert_eq!(
+            options.default_color,
+            Some(Color::TrueColor {
+                r: 255,
+                g: 175,
+                b: 0
+            })
+        );
+    }
+
+    #[test]
+    fn slow_color_takes_any_rule_color() {
+        let (options, _) = parse_args(["--slow-color=#ff8800", "prog"].map(String::from).to_vec());
+        assert_eq!(
+            options.slow_color,
+            Some(Color::TrueColor {
+                r: 255,
+                g: 136,
+                b: 0
+            })
+        );
+    }
+
+    #[test]
+    fn tabs_expand_to_the_next_tab_stop() {
+        assert_eq!(expand_tabs("a\tbc\t\td", 4), "a   bc      d");
+        assert_eq!(expand_tabs("abcd\te", 4), "abcd    e");
+        // Wide characters take two columns, escapes none
+        assert_eq!(expand_tabs("漢\tx", 4), "漢  x");
+        assert_eq!(
+            expand_tabs("\x1b[31ma\x1b[0m\tx", 4),
+            "\x1b[31ma\x1b[0m   x"
+        );
+    }
+
+    #[test]
+    fn multicall_names_come_from_argv0() {
+        assert_eq!(multicall_name("/usr/local/bin/ls").as_deref(), Some("ls"));
+        assert_eq!(multicall_name("ls").as_deref(), Some("ls"));
+        assert_eq!(multicall_name("/usr/bin/nscwrs"), None);
+        assert_eq!(multicall_name(""), None);
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("2m"), Some(2 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("1T"), None);
+    }
+
+    #[test]
+    fn subcommand_rule_file_sits_in_a_d_directory_or_the_rule_directory() {
+        let dir = std::env::temp_dir().join(format!("nscwrs-{}-subcommand", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("cargo.d")).unwrap();
+        fs::create_dir_all(dir.join("git")).unwrap();
+        fs::write(dir.join("cargo"), "").unwrap();
+        fs::write(dir.join("cargo.d/test"), "").unwrap();
+        fs::write(dir.join("git/log"), "").unwrap();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            find_subcommand_rule_file(&dir.join("cargo"), &args(&["-q", "test", "x"])),
+            Some(dir.join("cargo.d/test"))
+        );
+        assert_eq!(
+            find_subcommand_rule_file(&dir.join("git"), &args(&["log"])),
+            Some(dir.join("git/log"))
+        );
+        assert_eq!(
+            find_subcommand_rule_file(&dir.join("cargo"), &args(&["build"])),
+            None
+        );
+        assert_eq!(
+            find_subcommand_rule_file(&dir.join("cargo"), &args(&["-q"])),
+            None
+        );
+        fs::write(dir.join("cargo.d/.hidden"), "").unwrap();
+        assert_eq!(
+            find_subcommand_rule_file(&dir.join("cargo"), &args(&[".hidden"])),
+            None
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn neutralized_controls_are_shown_in_caret_notation() {
+        assert_eq!(
+            neutralize_controls("a\x1b[2J\x07\tb\x7f\u{9b}31m"),
+            "a^[[2J^G\tb^?^[[31m"
+        );
+        assert_eq!(neutralize_controls("plain 日本"), "plain 日本");
+    }
+
+    #[test]
+    fn baseline_color_takes_any_rule_color() {
+        let (options, _) = parse_args(["--baseline-color", "4", "prog"].map(String::from).to_vec());
+        assert_eq!(options.baseline_color, Some(Color::Blue));
+    }
+
+    #[test]
+    fn table_columns_start_after_two_spaces_and_give_way_to_long_values() {
+        let starts = column_starts("CONTAINER ID  STATUS   NAME");
+        assert_eq!(starts, [0, 14, 23]);
+        let cells = |row: &str| table_cells(&row.chars().collect::<Vec<_>>(), &starts);
+        assert_eq!(
+            cells("abc           Up       web"),
+            [(0, 3), (14, 16), (23, 26)]
+        );
+        // Ragged: a cell may start late or early, as long as it is past two spaces
+        assert_eq!(
+            cells("abc            Up     web"),
+            [(0, 3), (15, 17), (22, 25)]
+        );
+        // A value running into the next column pushes it back
+        assert_eq!(
+            cells("longcontainerid12  Up  db"),
+            [(0, 17), (19, 21), (23, 25)]
+        );
+        assert_eq!(cells("abc"), [(0, 3), (3, 3), (3, 3)]);
+    }
+
+    #[test]
+    fn segments_are_whole_lines_whatever_the_buffer_size() {
+        let output = "a long first line\r\nsecond\n\nno newline";
+        for capacity in [1, 3, 8 * 1024] {
+            let reader = BufReader::with_capacity(capacity, output.as_bytes());
+            let segments: Vec<(String, Ending)> =
+                Segments::new(reader, false).map(Result::unwrap).collect();
+            assert_eq!(
+                segments,
+                [
+                    ("a long first line".to_string(), Ending::CrLf),
+                    ("second".to_string(), Ending::Lf),
+                    (String::new(), Ending::Lf),
+                    ("no newline".to_string(), Ending::Eof),
+                ],
+                "capacity {}",
+                capacity
+            );
         }
     }
 
-    let _ = child.wait().expect("Failed to wait on child process");
+    #[test]
+    fn selftest_passes_on_a_healthy_build() {
+        assert!(selftest());
+    }
 }
@@ -0,0 +1,244 @@
+//! Turning styled segments into output: ANSI escapes, HTML or plain text.
+
+use colored::{Color, ColoredString, Colorize, Styles};
+
+use crate::rules::{palette_color, palette_index};
+
+/// How a matched segment is to be styled, after all of its rule's directives
+/// have been resolved
+pub struct Style<'a> {
+    pub fg: Color,
+    pub bg: Option<Color>,
+    /// Raw SGR parameters (`sgr:`), which replace `fg`/`bg` when set
+    pub sgr: Option<&'a str>,
+    /// Text attributes on top of the colors (`style:`)
+    pub styles: &'a [Styles],
+}
+
+/// Output backend used by [`Colorizer`](crate::Colorizer)
+pub trait ColorRenderer {
+    /// `text` styled as `style`
+    fn styled(&self, text: &str, style: &Style) -> String;
+
+    /// `text` that no rule matched
+    fn plain(&self, text: &str) -> String {
+        text.to_string()
+    }
+}
+
+/// Terminal escapes, what the wrapper writes
+pub struct AnsiRenderer;
+
+impl ColorRenderer for AnsiRenderer {
+    fn styled(&self, text: &str, style: &Style) -> String {
+        if let Some(sgr) = style.sgr {
+            return format!("\x1b[{}m{}\x1b[0m", sgr, text);
+        }
+        let mut styled = text.color(style.fg);
+        if let Some(bg) = style.bg {
+            styled = styled.on_color(bg);
+        }
+        for &attr in style.styles {
+            styled = with_style(styled, attr);
+        }
+        styled.to_string()
+    }
+}
+
+/// `<span>`s with inline CSS, with all text HTML-escaped. `sgr:` segments have no
+/// CSS equivalent and get a `data-sgr` attribute instead.
+pub struct HtmlRenderer;
+
+impl ColorRenderer for HtmlRenderer {
+    fn styled(&self, text: &str, style: &Style) -> String {
+        if let Some(sgr) = style.sgr {
+            return format!("<span data-sgr=\"{}\">{}</span>", sgr, html_escape(text));
+        }
+        let mut css = format!("color:{}", css_color(style.fg));
+        if let Some(bg) = style.bg {
+            css.push_str(&format!(";background-color:{}", css_color(bg)));
+        }
+        for &attr in style.styles {
+            css.push_str(css_style(attr));
+        }
+        format!("<span style=\"{}\">{}</span>", css, html_escape(text))
+    }
+
+    fn plain(&self, text: &str) -> String {
+        html_escape(text)
+    }
+}
+
+/// Text unchanged, as if no rule had matched
+pub struct PlainRenderer;
+
+impl ColorRenderer for PlainRenderer {
+    fn styled(&self, text: &str, _style: &Style) -> String {
+        text.to_string()
+    }
+}
+
+/// Text delimiters around matches (`--marker '>>' '<<'`) for output that can't
+/// carry color, such as plain log files
+pub struct MarkerRenderer {
+    pub open: String,
+    pub close: String,
+}
+
+impl ColorRenderer for MarkerRenderer {
+    fn styled(&self, text: &str, _style: &Style) -> String {
+        format!("{}{}{}", self.open, text, self.close)
+    }
+}
+
+fn with_style(text: ColoredString, style: Styles) -> ColoredString {
+    match style {
+        Styles::Bold => text.bold(),
+        Styles::Dimmed => text.dimmed(),
+        Styles::Italic => text.italic(),
+        Styles::Underline => text.underline(),
+        Styles::Blink => text.blink(),
+        Styles::Reversed => text.reversed(),
+        Styles::Hidden => text.hidden(),
+        Styles::Strikethrough => text.strikethrough(),
+        Styles::Clear => text.clear(),
+    }
+}
+
+/// CSS declarations for a `style:` attribute. Blinking is left out, and reversed
+/// colors would need the fg and bg swapped, which the span doesn't do.
+fn css_style(style: Styles) -> &'static str {
+    match style {
+        Styles::Bold => ";font-weight:bold",
+        Styles::Dimmed => ";opacity:0.6",
+        Styles::Italic => ";font-style:italic",
+        Styles::Underline => ";text-decoration:underline",
+        Styles::Strikethrough => ";text-decoration:line-through",
+        Styles::Blink | Styles::Reversed | Styles::Hidden | Styles::Clear => "",
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// CSS value for `color`, using the xterm palette for the 16 named colors
+fn css_color(color: Color) -> String {
+    let (r, g, b) = rgb(color);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// WCAG contrast ratio of two colors, from 1 (same luminance) to 21 (black on white)
+pub(crate) fn contrast_ratio(first: Color, second: Color) -> f64 {
+    let (first, second) = (luminance(first), luminance(second));
+    (first.max(second) + 0.05) / (first.min(second) + 0.05)
+}
+
+/// Relative luminance of `color` as the xterm palette shows it
+fn luminance(color: Color) -> f64 {
+    let linear = |channel: u8| {
+        let channel = channel as f64 / 255.0;
+        if channel <= 0.03928 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let (r, g, b) = rgb(color);
+    0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b)
+}
+
+/// `color` as red, green and blue, with the xterm palette for the named colors
+fn rgb(color: Color) -> (u8, u8, u8) {
+    match color {
+        Color::Black => (0, 0, 0),
+        Color::Red => (205, 0, 0),
+        Color::Green => (0, 205, 0),
+        Color::Yellow => (205, 205, 0),
+        Color::Blue => (0, 0, 238),
+        Color::Magenta => (205, 0, 205),
+        Color::Cyan => (0, 205, 205),
+        Color::White => (229, 229, 229),
+        Color::BrightBlack => (127, 127, 127),
+        Color::BrightRed => (255, 0, 0),
+        Color::BrightGreen => (0, 255, 0),
+        Color::BrightYellow => (255, 255, 0),
+        Color::BrightBlue => (92, 92, 255),
+        Color::BrightMagenta => (255, 0, 255),
+        Color::BrightCyan => (0, 255, 255),
+        Color::BrightWhite => (255, 255, 255),
+        Color::TrueColor { r, g, b } => (r, g, b),
+        // Later `colored` releases have palette colors of their own
+        #[allow(unreachable_patterns)]
+        other => palette_index(other).map_or((229, 229, 229), |index| rgb(palette_color(index))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Colorizer;
+    use crate::rules::parse_color_rules;
+    use std::path::Path;
+
+    /// `line` colored by `rules` through `renderer`
+    fn render(renderer: impl ColorRenderer + Send + 'static, line: &str) -> String {
+        let rules = parse_color_rules("[fg:red,bg:blue] error\n[sgr:1] <b>\n", Path::new("."));
+        Colorizer::with_renderer(rules, renderer).colorize_line(line)
+    }
+
+    #[test]
+    fn renderers_style_the_same_matches() {
+        colored::control::set_override(true);
+        let line = "an error & <b>";
+        assert_eq!(
+            render(AnsiRenderer, line),
+            "an \x1b[44;31merror\x1b[0m & \x1b[1m<b>\x1b[0m"
+        );
+        assert_eq!(
+            render(HtmlRenderer, line),
+            "an <span style=\"color:#cd0000;background-color:#0000ee\">error</span> &amp; \
+             <span data-sgr=\"1\">&lt;b&gt;</span>"
+        );
+        assert_eq!(render(PlainRenderer, line), line);
+        let marker = MarkerRenderer {
+            open: ">>".to_string(),
+            close: "<<".to_string(),
+        };
+        assert_eq!(render(marker, line), "an >>error<< & >><b><<");
+    }
+
+    #[test]
+    fn style_attributes_become_escapes_and_css() {
+        colored::control::set_override(true);
+        let rules = parse_color_rules("[fg:red,style:bold,italic] error\n", Path::new("."));
+        let ansi = Colorizer::with_renderer(rules.clone(), AnsiRenderer).colorize_line("error");
+        assert_eq!(ansi, "\x1b[1;3;31merror\x1b[0m");
+        let html = Colorizer::with_renderer(rules, HtmlRenderer).colorize_line("error");
+        assert_eq!(
+            html,
+            "<span style=\"color:#cd0000;font-weight:bold;font-style:italic\">error</span>"
+        );
+    }
+
+    #[test]
+    fn contrast_ratio_runs_from_one_to_twenty_one() {
+        assert_eq!(contrast_ratio(Color::Red, Color::Red), 1.0);
+        assert!((contrast_ratio(Color::Black, Color::BrightWhite) - 21.0).abs() < 1e-9);
+        assert_eq!(
+            contrast_ratio(Color::Black, Color::White),
+            contrast_ratio(Color::White, Color::Black)
+        );
+    }
+}
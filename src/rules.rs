@@ -0,0 +1,3060 @@
+//! Rule file parsing: headers, directives and the loader.
+
+use crate::render::contrast_ratio;
+use aho_corasick::{AhoCorasick, MatchKind};
+use colored::{Color, Styles};
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use unicode_width::UnicodeWidthStr;
+
+const SECTION_BEGIN: &str = "# nscwrs-begin";
+const SECTION_END: &str = "# nscwrs-end";
+/// Below this fg/bg contrast ratio `--check-contrast` warns; identical colors are 1
+const MIN_CONTRAST: f64 = 1.5;
+/// Widest `pad:`, far wider than any terminal
+const MAX_PAD: usize = 4096;
+/// The loader gives up on a file with this many errors or rules, which is more
+/// likely some other file than a rule file (`NSCWRS_MAX_RULE_ERRORS`, `NSCWRS_MAX_RULES`)
+const MAX_ERRORS: usize = 50;
+const MAX_RULES: usize = 10_000;
+/// Compiled size in bytes a rule file's regex may take, so a pathological pattern
+/// fails to load instead of slowing every line down (`set_regex_size_limit`). The
+/// default is the `regex` crate's own.
+const REGEX_SIZE_LIMIT: usize = 10 << 20;
+static REGEX_SIZE: AtomicUsize = AtomicUsize::new(REGEX_SIZE_LIMIT);
+
+/// Set the largest compiled size, in bytes, of the regexes of rule files loaded
+/// from now on (`--regex-size-limit`). Larger ones are reported with their line
+/// and skipped. The default is 10 MiB.
+pub fn set_regex_size_limit(bytes: usize) {
+    REGEX_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+/// `RegexBuilder` for a regex from a rule file, with the size limits applied. The
+/// lazy DFA's cache gets a fifth of the size, the ratio of the `regex` defaults.
+fn limited_regex(pattern: &str) -> RegexBuilder {
+    let limit = REGEX_SIZE.load(Ordering::Relaxed);
+    let mut builder = RegexBuilder::new(pattern);
+    builder.size_limit(limit).dfa_size_limit(limit / 5);
+    builder
+}
+
+/// FNV-1a hash of `bytes`. Unlike the standard library's hasher it is fixed, so
+/// what is picked by it doesn't change between builds.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Whether `COLORFGBG` (`fg;bg`, set by rxvt, Konsole and others) says the terminal
+/// background is light. An unknown background counts as dark.
+pub fn is_light_background(colorfgbg: Option<&str>) -> bool {
+    let background = colorfgbg.and_then(|value| value.rsplit(';').next()?.parse::<u8>().ok());
+    // 7 (light gray) and the bright colors except 8 (dark gray) are light
+    matches!(background, Some(background) if background == 7 || background > 8)
+}
+
+/// One rule of a rule file, see [`Colorizer::explain`](crate::Colorizer::explain).
+/// Its `Display` shows the pattern and directives as `--dump-rules` does.
+#[derive(Clone)]
+pub struct Rule {
+    pub(crate) regex: Regex,
+    pub(crate) fg_color: Color,
+    pub(crate) bg_color: Option<Color>,
+    /// The fg was unknown or `default`, `RuleSet::set_default_color` replaces it
+    pub(crate) fg_fallback: bool,
+    /// Restrict coloring to these display columns (1-based, inclusive)
+    pub(crate) cols: Option<(usize, usize)>,
+    pub(crate) rest: Option<RestOfLine>,
+    /// Color matches by looking up the captured value (`map-file:` directive)
+    pub(crate) color_map: Option<HashMap<String, Color>>,
+    /// Foregrounds handed out by match position on the line (`cycle:` directive)
+    pub(crate) cycle: Option<Vec<Color>>,
+    /// Foreground picked by the matched percentage from a red to green gradient
+    /// (`progress:`)
+    pub(crate) progress: Option<Progress>,
+    /// Backgrounds taken in turn by successive matches, across lines (`alt-bg:`)
+    pub(crate) alt_bg: Option<Vec<Color>>,
+    /// Raw SGR parameters (`sgr:1;31`), used instead of fg/bg when set
+    pub(crate) sgr: Option<String>,
+    /// Text attributes added to the colors (`style:bold,underline`)
+    pub(crate) styles: Vec<Styles>,
+    pub(crate) case: Option<CaseTransform>,
+    /// Reformat matched numbers before coloring them (`format:`)
+    pub(crate) format: Option<NumberFormat>,
+    /// Pad matched text with spaces to a width, to line up columns (`pad:`)
+    pub(crate) pad: Option<Padding>,
+    /// Replace matched text with what this command prints for it (`filter-cmd:`)
+    pub(crate) filter_cmd: Option<String>,
+    /// Replace matched text with a mask (`redact:` directive)
+    pub(crate) redact: Option<Redact>,
+    /// No color was given, which only a `redact:`, `sink:` or `run:` rule may do: a
+    /// mask stays plain, and the other rules' matches are left to the rest
+    pub(crate) unstyled: bool,
+    /// Append the lines the rule matches to this file (`sink:`)
+    pub(crate) sink: Option<PathBuf>,
+    /// Shell command to run when the rule matches (`run:`)
+    pub(crate) run: Option<String>,
+    /// Put in front of every line the rule matches, styled like the match (`icon:`)
+    pub(crate) icon: Option<String>,
+    /// The rule only applies to lines matching `if:` and not matching `unless:`
+    pub(crate) guard_if: Option<Regex>,
+    pub(crate) guard_unless: Option<Regex>,
+    /// The rule only applies when the wrapped program's name matches (`prog:`)
+    pub(crate) guard_prog: Option<Regex>,
+    /// The rule only applies when the program's arguments match (`arg:`)
+    pub(crate) guard_args: Option<Regex>,
+    /// The rule only applies on terminals at least this wide (`min-cols:`)
+    pub(crate) min_cols: Option<usize>,
+    /// The rule only applies to this part of blank-line separated blocks (`block:`)
+    pub(crate) block: Option<BlockPosition>,
+    /// The rule only applies when one of the previous N lines matched the regex
+    /// (`needs:`, with N from `context-prev:`)
+    pub(crate) needs: Option<(Regex, usize)>,
+    /// Ring the terminal bell when this rule matches (`bell:true`)
+    pub(crate) bell: bool,
+    /// A match makes the run count as failed / passed (`fail-on:`, `pass-on:`)
+    pub(crate) fail_on: bool,
+    pub(crate) pass_on: bool,
+    /// How bad a match is, for `--exit-on-severity` (`severity:`)
+    pub(crate) severity: Option<Severity>,
+    /// Only lines a `keep:true` rule matches are shown, and none a `drop:true` one
+    /// matches
+    pub(crate) keep: bool,
+    pub(crate) drop: bool,
+    /// Match on a field of a JSON line instead of the text (`json:` pattern)
+    pub(crate) json: Option<JsonField>,
+    /// Match on a pair of a logfmt line instead (`logfmt:`, `logfmt-key:` pattern)
+    pub(crate) logfmt: Option<LogfmtField>,
+    /// Match any term of a word list instead (`wordlist:` pattern)
+    pub(crate) wordlist: Option<Wordlist>,
+    /// Color the whole line when the rule matches (`line:true`)
+    pub(crate) whole_line: bool,
+    /// Fraction of matches to keep (`sample:0.1`), see `Rule::sampled`
+    pub(crate) sample: Option<f64>,
+    /// Stop coloring after this many shown matches over the whole run (`global-count:`)
+    pub(crate) global_count: Option<usize>,
+    /// Only every Nth line the rule matches, counted over the whole run (`every:`)
+    pub(crate) every: Option<usize>,
+    /// A value the rule colored isn't colored again for this many lines (`dedup-window:`)
+    pub(crate) dedup_window: Option<usize>,
+    /// Only the Nth match on a line, counted from the end when negative (`occurrence:`)
+    pub(crate) occurrence: Option<isize>,
+    /// Only matches X to Y on a line (1-based, inclusive), the others stay plain (`range:`)
+    pub(crate) range: Option<(usize, usize)>,
+    /// Match against the Nth whitespace-separated field only (`field:`), from the
+    /// end when negative
+    pub(crate) field: Option<isize>,
+    /// A `field:` rule's field must also match this as a whole (`matches:`)
+    pub(crate) field_matches: Option<Regex>,
+    /// Position in the rule list, rules are sorted on it after loading (`order:`)
+    pub(crate) order: i32,
+    /// Rule of the `@final` pass, applied once the whole output is known
+    pub(crate) aggregate: Option<Aggregate>,
+    /// Index into `RuleSet::sections` when defined inside `@when` ... `@until`
+    pub(crate) section: Option<usize>,
+    /// Output stream the rule is scoped to by `@stream`
+    pub(crate) stream: StreamScope,
+    /// Regex flag letters from `flags:`, kept for `--dump-rules`
+    pub(crate) flags: String,
+    /// Matches inside a quoted string don't count (`@skip-quotes true`)
+    pub(crate) skip_quotes: bool,
+    /// Line of the rule file the regex was read from
+    pub(crate) line: usize,
+}
+
+/// `json:level=ERROR`: the line must be a JSON object whose `level` field (a
+/// dotted path for nested objects) equals `ERROR`
+#[derive(Clone)]
+pub(crate) struct JsonField {
+    pub(crate) path: Vec<String>,
+    pub(crate) value: String,
+}
+
+impl JsonField {
+    fn parse(spec: &str) -> Option<Self> {
+        let (path, value) = spec.split_once('=')?;
+        let path: Vec<String> = path.trim().split('.').map(str::to_string).collect();
+        if path.iter().any(String::is_empty) {
+            return None;
+        }
+        Some(JsonField {
+            path,
+            value: value.trim().to_string(),
+        })
+    }
+
+    /// Whether `object` has the field with the expected value. Strings are compared
+    /// by content, anything else by reading the expected value as JSON (`200`,
+    /// `true`, `null`).
+    pub(crate) fn matches(&self, object: &serde_json::Value) -> bool {
+        let field = self
+            .path
+            .iter()
+            .try_fold(object, |value, key| value.get(key.as_str()));
+        match field {
+            Some(serde_json::Value::String(text)) => *text == self.value,
+            Some(other) => serde_json::from_str::<serde_json::Value>(&self.value)
+                .is_ok_and(|expected| expected == *other),
+            None => false,
+        }
+    }
+
+    /// Regex finding where the field's value sits in the raw line, with the value
+    /// (without quotes) as its first group. The innermost key is searched for,
+    /// which is ambiguous only when that key name is used more than once.
+    fn locator(&self) -> Regex {
+        let key = regex::escape(self.path.last().map_or("", String::as_str));
+        Regex::new(&format!(
+            r#""{}"\s*:\s*(?:"((?:[^"\\]|\\.)*)"|([^,}}\]\s]+))"#,
+            key
+        ))
+        .expect("JSON locator regex is valid")
+    }
+}
+
+/// `wordlist:FILE`: the literal terms of a file, one per line, found all at once by
+/// an Aho-Corasick automaton, which stays fast for thousands of terms where one
+/// big alternation regex would not
+#[derive(Clone)]
+pub(crate) struct Wordlist {
+    path: PathBuf,
+    pub(crate) terms: AhoCorasick,
+}
+
+impl Wordlist {
+    /// Blank lines and lines starting with `#` are skipped; at overlapping terms the
+    /// leftmost one wins, and of those the longest
+    fn load(path: &Path, case_insensitive: bool) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read wordlist {} ({})", path.display(), err))?;
+        let terms: Vec<&str> = content
+            .lines()
+            .map(str::trim)
+            .filter(|term| !term.is_empty() && !term.starts_with('#'))
+            .collect();
+        if terms.is_empty() {
+            return Err(format!("Wordlist {} has no terms", path.display()));
+        }
+        let terms = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .ascii_case_insensitive(case_insensitive)
+            .build(terms)
+            .map_err(|err| format!("Invalid wordlist {} ({})", path.display(), err))?;
+        Ok(Wordlist {
+            path: path.to_path_buf(),
+            terms,
+        })
+    }
+}
+
+/// `logfmt:level=error`: the line must be logfmt (`key=value key2="a b"`) with a
+/// `level` pair whose value, unquoted, is `error`. The key may be `*` for any key
+/// and the value left out for any value (`logfmt:level`, `logfmt:*`).
+#[derive(Clone)]
+pub(crate) struct LogfmtField {
+    /// `None` for `*`
+    pub(crate) key: Option<String>,
+    pub(crate) value: Option<String>,
+    /// The key is colored instead of the value (`logfmt-key:`)
+    pub(crate) color_key: bool,
+}
+
+impl LogfmtField {
+    fn parse(spec: &str, color_key: bool) -> Option<Self> {
+        let (key, value) = match spec.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim().to_string())),
+            None => (spec.trim(), None),
+        };
+        if key.is_empty() || key.contains(|ch: char| ch.is_whitespace() || ch == '"') {
+            return None;
+        }
+        Some(LogfmtField {
+            key: (key != "*").then(|| key.to_string()),
+            value,
+            color_key,
+        })
+    }
+
+    /// Whether a pair of this key and (unescaped) value is one to color
+    pub(crate) fn matches(&self, key: &str, value: &str) -> bool {
+        self.key.as_ref().is_none_or(|expected| expected == key)
+            && self.value.as_ref().is_none_or(|expected| expected == value)
+    }
+
+    /// Regex finding the pair in the raw line, what `Rule::pattern` shows
+    fn locator(&self) -> Regex {
+        let key = self
+            .key
+            .as_deref()
+            .map_or(r#"[^\s="]+"#.to_string(), regex::escape);
+        Regex::new(&format!(
+            r#"(?:^|\s)({})(?:=("(?:[^"\\]|\\.)*"|\S*))?"#,
+            key
+        ))
+        .expect("logfmt locator regex is valid")
+    }
+}
+
+impl std::fmt::Display for LogfmtField {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let prefix = if self.color_key {
+            "logfmt-key"
+        } else {
+            "logfmt"
+        };
+        write!(f, "{}:{}", prefix, self.key.as_deref().unwrap_or("*"))?;
+        if let Some(value) = &self.value {
+            write!(f, "={}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a `@final` rule looks for over all lines
+#[derive(Clone, Copy)]
+pub(crate) enum Aggregate {
+    /// `max:<regex>`: the line with the largest number captured by the first
+    /// group (or the whole match); the first such line on ties
+    Max,
+    /// `rank:top=N`: the N lines with the largest such numbers, each line counted by
+    /// its largest; of lines with the same number, the earlier ranks higher
+    Top(usize),
+}
+
+/// `severity:` levels, from least to most severe
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warn,
+    Error,
+}
+
+impl Severity {
+    /// The exit code `--exit-on-severity` gives a run where this is the worst match
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Severity::Warn => 1,
+            Severity::Error => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// Which of the child's output streams a rule applies to
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum StreamScope {
+    Stdout,
+    Stderr,
+    #[default]
+    Both,
+}
+
+impl StreamScope {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "stdout" => Some(StreamScope::Stdout),
+            "stderr" => Some(StreamScope::Stderr),
+            "both" => Some(StreamScope::Both),
+            _ => None,
+        }
+    }
+
+    /// Whether a rule with this scope applies to `stream`
+    pub(crate) fn includes(self, stream: StreamScope) -> bool {
+        self == StreamScope::Both || stream == StreamScope::Both || self == stream
+    }
+}
+
+/// Rules only active between a trigger line and a reset line
+#[derive(Clone)]
+pub(crate) struct Section {
+    pub(crate) when: Regex,
+    pub(crate) until: Option<Regex>,
+}
+
+impl Section {
+    /// Whether the section's rules apply to `line`, moving the triggered state along.
+    /// The trigger and reset lines themselves are not colored by the section.
+    pub(crate) fn advance(&self, triggered: &mut bool, line: &str) -> bool {
+        if !*triggered {
+            *triggered = self.when.is_match(line);
+            return false;
+        }
+        if self
+            .until
+            .as_ref()
+            .is_some_and(|until| until.is_match(line))
+        {
+            *triggered = false;
+            return false;
+        }
+        true
+    }
+}
+
+/// Everything loaded from a rule file
+#[derive(Clone, Default)]
+pub struct RuleSet {
+    pub(crate) rules: Vec<Rule>,
+    pub(crate) sections: Vec<Section>,
+    /// Problems found while loading, the affected definitions were skipped
+    pub diagnostics: Vec<Diagnostic>,
+    /// Real program to run instead of the one named like the wrapper (`@exec`)
+    pub exec: Option<String>,
+    /// Environment variables set for the real program (`@env KEY=VALUE`), in order
+    pub env: Vec<(String, String)>,
+    /// On overlapping matches the later rule wins (`@cascade last`)
+    pub last_wins: bool,
+    /// What unknown and `default` fg colors resolve to (`@default-color`)
+    pub default_color: Option<Color>,
+    /// Match on the text around the program's own escape sequences and keep them
+    /// in the output (`@preserve-escapes`)
+    pub preserve_escapes: bool,
+}
+
+/// A problem in a rule file, tied to the line it was found on
+#[derive(Clone)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub message: String,
+}
+
+impl RuleSet {
+    /// Layer `self` over `lower`: on overlapping matches `self`'s rules win, and
+    /// its `@exec` is kept if it has one. Diagnostics keep their own line numbers.
+    pub fn merge(&mut self, mut lower: RuleSet) {
+        // Winning means coming first, or coming last under `@cascade last`
+        let last_wins = self.last_wins || lower.last_wins;
+        let exec = self.exec.take().or(lower.exec.take());
+        // Set in order, so `self`'s values come last and win
+        let mut env = std::mem::take(&mut lower.env);
+        env.append(&mut self.env);
+        let default_color = self.default_color.or(lower.default_color);
+        let preserve_escapes = self.preserve_escapes || lower.preserve_escapes;
+        let mut later = lower;
+        if last_wins {
+            std::mem::swap(self, &mut later);
+        }
+        let offset = self.sections.len();
+        self.rules.extend(later.rules.into_iter().map(|mut rule| {
+            rule.section = rule.section.map(|section| section + offset);
+            rule
+        }));
+        self.sections.extend(later.sections);
+        self.diagnostics.extend(later.diagnostics);
+        self.exec = exec;
+        self.env = env;
+        self.last_wins = last_wins;
+        self.default_color = default_color;
+        self.preserve_escapes = preserve_escapes;
+    }
+
+    /// Resolve every unknown or `default` fg of the rules to `color` (white until
+    /// set). Can be called again, e.g. to override `@default-color`.
+    pub fn set_default_color(&mut self, color: Color) {
+        for rule in self.rules.iter_mut().filter(|rule| rule.fg_fallback) {
+            rule.fg_color = color;
+        }
+    }
+
+    /// Append a rule given as `pattern=color` or `pattern=<directives>`, as with
+    /// `--highlight 'ERROR=red'` or `--highlight 'WARN=fg:black,bg:yellow'`
+    pub fn add_highlight(&mut self, spec: &str) -> Result<(), String> {
+        let (pattern, style) = spec
+            .rsplit_once('=')
+            .ok_or("expected pattern=color or pattern=directives")?;
+        let mut added = RuleSet::default();
+        let options = match parse_color_token(style) {
+            Some(fg) => RuleOptions {
+                fg: Some(fg),
+                ..RuleOptions::default()
+            },
+            None => parse_colors(style, 0, Path::new("."), &mut added.diagnostics),
+        };
+        if !options.has_color() && !options.may_be_uncolored() {
+            return Err(format!("no color in {}", style));
+        }
+        add_rule(
+            &mut added,
+            &options,
+            pattern,
+            0,
+            Scope::default(),
+            Path::new("."),
+        );
+        if !added.diagnostics.is_empty() {
+            let messages: Vec<String> = added.diagnostics.into_iter().map(|d| d.message).collect();
+            return Err(messages.join(", "));
+        }
+        self.rules.extend(added.rules);
+        Ok(())
+    }
+
+    /// `--check-contrast`: one warning per rule whose foreground (including `cycle:`
+    /// and `map-file:` colors) is hard to tell from its background or `alt-bg:`
+    /// colors. `sgr:` rules are left alone, their escapes aren't interpreted.
+    pub fn contrast_warnings(&self) -> Vec<Diagnostic> {
+        let mut warnings = Vec::new();
+        for rule in self.rules.iter().filter(|rule| rule.sgr.is_none()) {
+            let backgrounds = match &rule.alt_bg {
+                Some(colors) => colors.clone(),
+                None => rule.bg_color.into_iter().collect(),
+            };
+            let foregrounds = std::iter::once(rule.fg_color)
+                .chain(rule.cycle.iter().flatten().copied())
+                .chain(rule.color_map.iter().flat_map(|map| map.values().copied()));
+            let unreadable = foregrounds
+                .flat_map(|fg| backgrounds.iter().map(move |&bg| (fg, bg)))
+                .find(|&(fg, bg)| contrast_ratio(fg, bg) < MIN_CONTRAST);
+            if let Some((fg, bg)) = unreadable {
+                warnings.push(Diagnostic::new(
+                    rule.line,
+                    format!(
+                        "fg:{} on bg:{} is hard to read (contrast {:.1}:1)",
+                        color_name(fg),
+                        color_name(bg),
+                        contrast_ratio(fg, bg)
+                    ),
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Drop the rules whose `prog:` doesn't match `program`, the name of the wrapped
+    /// program, so one shared rule file can hold program-specific rules
+    pub fn for_program(&mut self, program: &str) {
+        self.rules.retain(|rule| {
+            rule.guard_prog
+                .as_ref()
+                .is_none_or(|re| re.is_match(program))
+        });
+    }
+
+    /// Drop the rules whose `arg:` doesn't match the program's arguments, joined
+    /// with spaces
+    pub fn for_args(&mut self, args: &[String]) {
+        let args = args.join(" ");
+        self.rules
+            .retain(|rule| rule.guard_args.as_ref().is_none_or(|re| re.is_match(&args)));
+    }
+
+    /// Drop the rules whose `min-cols:` is wider than a terminal of `cols` columns
+    pub fn for_width(&mut self, cols: usize) {
+        self.rules
+            .retain(|rule| rule.min_cols.is_none_or(|min_cols| cols >= min_cols));
+    }
+
+    /// Separator `format:thousands` rules group digits with (`--thousands-sep`),
+    /// `,` unless set
+    pub fn set_thousands_sep(&mut self, sep: char) {
+        for rule in &mut self.rules {
+            if let Some(NumberFormat::Thousands(rule_sep)) = &mut rule.format {
+                *rule_sep = sep;
+            }
+        }
+    }
+
+    /// The rules in the order they are applied
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Whether `@final` rules need the whole output before anything is shown
+    pub fn has_final_rules(&self) -> bool {
+        self.rules.iter().any(|rule| rule.aggregate.is_some())
+    }
+
+    /// Whether any rule was explicitly scoped to stderr with `@stream stderr`
+    pub fn scopes_stderr(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.stream == StreamScope::Stderr)
+    }
+
+    /// Whether a `redact:` rule applies to stderr, which then has to go through
+    /// the rules so the secret doesn't get out there
+    pub fn redacts_stderr(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.redact.is_some() && rule.stream != StreamScope::Stdout)
+    }
+}
+
+/// Normalized listing of what was loaded (`--dump-rules`): one line per rule
+/// with every directive that differs from the default, then the sections
+impl std::fmt::Display for RuleSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(exec) = &self.exec {
+            writeln!(f, "@exec {}", exec)?;
+        }
+        for (key, value) in &self.env {
+            writeln!(f, "@env {}={}", key, value)?;
+        }
+        if self.last_wins {
+            writeln!(f, "@cascade last")?;
+        }
+        for (idx, rule) in self.rules.iter().enumerate() {
+            writeln!(f, "rule {} (line {}): {}", idx, rule.line, rule)?;
+        }
+        for (idx, section) in self.sections.iter().enumerate() {
+            write!(f, "section {}: @when {}", idx, section.when.as_str())?;
+            if let Some(until) = &section.until {
+                write!(f, " @until {}", until.as_str())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The rule's pattern and every directive that differs from the default, as
+/// listed by `--dump-rules`
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.json, &self.logfmt, &self.wordlist, self.aggregate) {
+            (Some(field), ..) => write!(f, "json:{}={}", field.path.join("."), field.value)?,
+            (None, Some(field), ..) => write!(f, "{}", field)?,
+            (None, None, Some(wordlist), _) => write!(f, "wordlist:{}", wordlist.path.display())?,
+            (None, None, None, Some(Aggregate::Max)) => write!(f, "max:/{}/", self.regex.as_str())?,
+            (None, None, None, Some(Aggregate::Top(count))) => {
+                write!(f, "/{}/ rank:top={}", self.regex.as_str(), count)?
+            }
+            (None, None, None, None) => write!(f, "/{}/", self.regex.as_str())?,
+        }
+        match &self.sgr {
+            Some(sgr) => write!(f, " sgr:{}", sgr)?,
+            None if self.unstyled => {}
+            None => write!(f, " fg:{}", color_name(self.fg_color))?,
+        }
+        if let Some(bg) = self.bg_color {
+            write!(f, " bg:{}", color_name(bg))?;
+        }
+        if !self.styles.is_empty() {
+            let names: Vec<&str> = self.styles.iter().map(|&style| style_name(style)).collect();
+            write!(f, " style:{}", names.join(","))?;
+        }
+        if !self.flags.is_empty() {
+            write!(f, " flags:{}", self.flags)?;
+        }
+        if let Some((first, last)) = self.cols {
+            match last {
+                usize::MAX => write!(f, " cols:{}-", first)?,
+                last => write!(f, " cols:{}-{}", first, last)?,
+            }
+        }
+        match self.rest {
+            Some(RestOfLine::Include) => write!(f, " rest:include")?,
+            Some(RestOfLine::After) => write!(f, " rest:after")?,
+            None => {}
+        }
+        if let Some(cycle) = &self.cycle {
+            let names: Vec<String> = cycle.iter().map(|&color| color_name(color)).collect();
+            write!(f, " cycle:{}", names.join(","))?;
+        }
+        match self.progress {
+            Some(Progress::RedToGreen) => write!(f, " progress:true")?,
+            Some(Progress::GreenToRed) => write!(f, " progress:reverse")?,
+            None => {}
+        }
+        if let Some(alt_bg) = &self.alt_bg {
+            let names: Vec<String> = alt_bg.iter().map(|&color| color_name(color)).collect();
+            write!(f, " alt-bg:{}", names.join(","))?;
+        }
+        if let Some(map) = &self.color_map {
+            write!(f, " map-file:({} entries)", map.len())?;
+        }
+        match self.case {
+            Some(CaseTransform::Upper) => write!(f, " case:upper")?,
+            Some(CaseTransform::Lower) => write!(f, " case:lower")?,
+            Some(CaseTransform::Title) => write!(f, " case:title")?,
+            None => {}
+        }
+        if let Some(NumberFormat::Thousands(_)) = self.format {
+            write!(f, " format:thousands")?;
+        }
+        if let Some(cmd) = &self.filter_cmd {
+            write!(f, " filter-cmd:{}", cmd)?;
+        }
+        match self.pad {
+            Some(Padding { width, right: true }) => write!(f, " pad:>{}", width)?,
+            Some(Padding { width, .. }) => write!(f, " pad:{}", width)?,
+            None => {}
+        }
+        match self.redact {
+            Some(Redact::Mask) => write!(f, " redact:true")?,
+            Some(Redact::KeepLength) => write!(f, " redact:length")?,
+            None => {}
+        }
+        if let Some(guard) = &self.guard_if {
+            write!(f, " if:{}", guard.as_str())?;
+        }
+        if let Some(guard) = &self.guard_unless {
+            write!(f, " unless:{}", guard.as_str())?;
+        }
+        if let Some(guard) = &self.guard_prog {
+            write!(f, " prog:{}", guard.as_str())?;
+        }
+        if let Some(guard) = &self.guard_args {
+            write!(f, " arg:{}", guard.as_str())?;
+        }
+        if let Some(cols) = self.min_cols {
+            write!(f, " min-cols:{}", cols)?;
+        }
+        match self.block {
+            Some(BlockPosition::First) => write!(f, " block:first")?,
+            Some(BlockPosition::Rest) => write!(f, " block:rest")?,
+            None => {}
+        }
+        if let Some((needs, lines)) = &self.needs {
+            write!(f, " context-prev:{} needs:{}", lines, needs.as_str())?;
+        }
+        if self.bell {
+            write!(f, " bell:true")?;
+        }
+        if let Some(sink) = &self.sink {
+            write!(f, " sink:{}", sink.display())?;
+        }
+        if let Some(run) = &self.run {
+            write!(f, " run:{}", run)?;
+        }
+        if let Some(icon) = &self.icon {
+            write!(f, " icon:{}", icon)?;
+        }
+        if self.fail_on {
+            write!(f, " fail-on:true")?;
+        }
+        if self.pass_on {
+            write!(f, " pass-on:true")?;
+        }
+        if let Some(severity) = self.severity {
+            write!(f, " severity:{}", severity.name())?;
+        }
+        if self.keep {
+            write!(f, " keep:true")?;
+        }
+        if self.drop {
+            write!(f, " drop:true")?;
+        }
+        if self.skip_quotes {
+            write!(f, " skip-quotes:true")?;
+        }
+        if self.whole_line {
+            write!(f, " line:true")?;
+        }
+        if self.order != 0 {
+            write!(f, " order:{}", self.order)?;
+        }
+        if let Some(rate) = self.sample {
+            write!(f, " sample:{}", rate)?;
+        }
+        if let Some(limit) = self.global_count {
+            write!(f, " global-count:{}", limit)?;
+        }
+        if let Some(every) = self.every {
+            write!(f, " every:{}", every)?;
+        }
+        if let Some(window) = self.dedup_window {
+            write!(f, " dedup-window:{}", window)?;
+        }
+        if let Some(occurrence) = self.occurrence {
+            write!(f, " occurrence:{}", occurrence)?;
+        }
+        if let Some((first, last)) = self.range {
+            match last {
+                usize::MAX => write!(f, " range:{}-", first)?,
+                last => write!(f, " range:{}-{}", first, last)?,
+            }
+        }
+        if let Some(field) = self.field {
+            write!(f, " field:{}", field)?;
+        }
+        if let Some(re) = &self.field_matches {
+            write!(f, " matches:{}", re.as_str())?;
+        }
+        if let Some(section) = self.section {
+            write!(f, " section:{}", section)?;
+        }
+        match self.stream {
+            StreamScope::Stdout => write!(f, " stream:stdout")?,
+            StreamScope::Stderr => write!(f, " stream:stderr")?,
+            StreamScope::Both => {}
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Diagnostic {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// Extend a match to the end of the line (`rest:` directive)
+#[derive(Clone, Copy)]
+pub(crate) enum RestOfLine {
+    /// Style from the start of the match, matched text included
+    Include,
+    /// Style only what follows the match
+    After,
+}
+
+/// Everything that can appear inside a `[...]` rule header
+#[derive(Default)]
+struct RuleOptions {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    fg_fallback: bool,
+    cols: Option<(usize, usize)>,
+    rest: Option<RestOfLine>,
+    color_map: Option<HashMap<String, Color>>,
+    cycle: Option<Vec<Color>>,
+    progress: Option<Progress>,
+    alt_bg: Option<Vec<Color>>,
+    sgr: Option<String>,
+    styles: Vec<Styles>,
+    /// Regex flag letters from `flags:` (see `build_regex`)
+    flags: String,
+    case: Option<CaseTransform>,
+    format: Option<NumberFormat>,
+    pad: Option<Padding>,
+    filter_cmd: Option<String>,
+    redact: Option<Redact>,
+    sink: Option<PathBuf>,
+    run: Option<String>,
+    icon: Option<String>,
+    guard_if: Option<Regex>,
+    guard_unless: Option<Regex>,
+    guard_prog: Option<Regex>,
+    guard_args: Option<Regex>,
+    min_cols: Option<usize>,
+    block: Option<BlockPosition>,
+    needs: Option<Regex>,
+    context_prev: Option<usize>,
+    bell: bool,
+    fail_on: bool,
+    pass_on: bool,
+    severity: Option<Severity>,
+    keep: bool,
+    drop: bool,
+    whole_line: bool,
+    sample: Option<f64>,
+    global_count: Option<usize>,
+    every: Option<usize>,
+    dedup_window: Option<usize>,
+    occurrence: Option<isize>,
+    rank: Option<usize>,
+    range: Option<(usize, usize)>,
+    field: Option<isize>,
+    field_matches: Option<Regex>,
+    order: i32,
+}
+
+impl RuleOptions {
+    /// Whether a foreground is given, which every rule but a `redact:`, `sink:`, `run:`,
+    /// `keep:` or `drop:` one needs
+    fn has_color(&self) -> bool {
+        self.fg.is_some() || self.sgr.is_some() || self.cycle.is_some() || self.progress.is_some()
+    }
+
+    /// Whether the rule does something without a color (`redact:`, `sink:`, `run:`,
+    /// `keep:`, `drop:`)
+    fn may_be_uncolored(&self) -> bool {
+        self.redact.is_some() || self.sink.is_some() || self.run.is_some() || self.keep || self.drop
+    }
+}
+
+/// Where a line sits in its block of non-blank lines (`block:` directive). Blank
+/// lines belong to no block.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum BlockPosition {
+    /// The first line after a blank one, or of the output
+    First,
+    /// Every other line of the block
+    Rest,
+}
+
+/// Recase matched text before styling (`case:` directive)
+#[derive(Clone, Copy)]
+pub(crate) enum CaseTransform {
+    Upper,
+    Lower,
+    /// Uppercase the first letter of every word, lowercase the rest
+    Title,
+}
+
+impl CaseTransform {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "upper" => Some(CaseTransform::Upper),
+            "lower" => Some(CaseTransform::Lower),
+            "title" => Some(CaseTransform::Title),
+            _ => None,
+        }
+    }
+
+    /// Full Unicode case mapping, so the result may be longer than the input (`ß` -> `SS`)
+    pub(crate) fn apply(self, text: &str) -> String {
+        match self {
+            CaseTransform::Upper => text.to_uppercase(),
+            CaseTransform::Lower => text.to_lowercase(),
+            CaseTransform::Title => {
+                let mut result = String::with_capacity(text.len());
+                let mut word_start = true;
+                for ch in text.chars() {
+                    if word_start {
+                        result.extend(ch.to_uppercase());
+                    } else {
+                        result.extend(ch.to_lowercase());
+                    }
+                    word_start = !ch.is_alphanumeric();
+                }
+                result
+            }
+        }
+    }
+}
+
+/// Direction of the `progress:` gradient
+#[derive(Clone, Copy)]
+pub(crate) enum Progress {
+    /// `progress:true`: 0% red, 50% yellow, 100% green
+    RedToGreen,
+    /// `progress:reverse`: 0% green, 100% red, e.g. for disk usage
+    GreenToRed,
+}
+
+impl Progress {
+    /// Truecolor for `percent`, clamped to 0-100
+    pub(crate) fn color(self, percent: f64) -> Color {
+        let mut t = percent.clamp(0.0, 100.0) / 100.0;
+        if let Progress::GreenToRed = self {
+            t = 1.0 - t;
+        }
+        // Red to yellow over the first half, yellow to green over the second
+        let (r, g) = if t < 0.5 {
+            (1.0, t * 2.0)
+        } else {
+            ((1.0 - t) * 2.0, 1.0)
+        };
+        Color::TrueColor {
+            r: (r * 255.0).round() as u8,
+            g: (g * 255.0).round() as u8,
+            b: 0,
+        }
+    }
+}
+
+/// The number at the start of `text`, such as `42` in `42%` or `99.5 %`
+fn parse_percentage(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let end = text
+        .find(|ch: char| !(ch.is_ascii_digit() || ch == '.'))
+        .unwrap_or(text.len());
+    text[..end].parse().ok()
+}
+
+/// How `format:` rewrites a matched number
+#[derive(Clone, Copy)]
+pub(crate) enum NumberFormat {
+    /// `format:thousands`: the integer digits in groups of three, `1234567` ->
+    /// `1,234,567`, with the separator from `RuleSet::set_thousands_sep`
+    Thousands(char),
+}
+
+impl NumberFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "thousands" => Some(NumberFormat::Thousands(',')),
+            _ => None,
+        }
+    }
+
+    /// `text` reformatted, `None` when it isn't a plain decimal number (an
+    /// optional sign, digits and an optional fraction)
+    pub(crate) fn apply(self, text: &str) -> Option<String> {
+        let NumberFormat::Thousands(sep) = self;
+        let (sign, unsigned) = match text.strip_prefix(['-', '+']) {
+            Some(unsigned) => (&text[..1], unsigned),
+            None => ("", text),
+        };
+        let (digits, fraction) = match unsigned.split_once('.') {
+            Some((digits, fraction)) => (digits, Some(fraction)),
+            None => (unsigned, None),
+        };
+        let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(digits) || fraction.is_some_and(|fraction| !is_digits(fraction)) {
+            return None;
+        }
+
+        let mut result = String::with_capacity(text.len() + digits.len() / 3 * sep.len_utf8());
+        result.push_str(sign);
+        for (idx, digit) in digits.chars().enumerate() {
+            if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+                result.push(sep);
+            }
+            result.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            result.push('.');
+            result.push_str(fraction);
+        }
+        Some(result)
+    }
+}
+
+/// `pad:N` fills matched text up with spaces to `N` display columns after it,
+/// `pad:>N` before it. Longer text is left as it is.
+#[derive(Clone, Copy)]
+pub(crate) struct Padding {
+    pub(crate) width: usize,
+    /// Right-aligned (`>`), the spaces come first
+    pub(crate) right: bool,
+}
+
+impl Padding {
+    fn parse(value: &str) -> Option<Self> {
+        let (right, width) = match value.strip_prefix('>') {
+            Some(width) => (true, width),
+            None => (false, value),
+        };
+        let width = width
+            .parse()
+            .ok()
+            .filter(|width| (1..=MAX_PAD).contains(width))?;
+        Some(Padding { width, right })
+    }
+
+    /// `text` padded, `None` when it is already as wide
+    pub(crate) fn apply(self, text: &str) -> Option<String> {
+        let fill = self
+            .width
+            .checked_sub(text.width())
+            .filter(|&fill| fill > 0)?;
+        let spaces = " ".repeat(fill);
+        Some(if self.right {
+            spaces + text
+        } else {
+            format!("{}{}", text, spaces)
+        })
+    }
+}
+
+/// What `redact:` puts in place of the matched text
+#[derive(Clone, Copy)]
+pub(crate) enum Redact {
+    /// `redact:true`: always `REDACT_MASK`, which doesn't give the length away
+    Mask,
+    /// `redact:length`: one `*` per character
+    KeepLength,
+}
+
+/// Mask written by `redact:true`
+const REDACT_MASK: &str = "****";
+
+impl Redact {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "true" => Some(Redact::Mask),
+            "length" => Some(Redact::KeepLength),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn apply(self, text: &str) -> String {
+        match self {
+            Redact::Mask => REDACT_MASK.to_string(),
+            Redact::KeepLength => "*".repeat(text.chars().count()),
+        }
+    }
+}
+
+impl Rule {
+    /// Line of the rule file the rule's pattern was read from, 0 for rules not
+    /// read from a file (`--highlight`)
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The regex as compiled, including what a pattern macro or `json:` turned into
+    pub fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+
+    /// Whether the rule styles its matches. Rules without a color (`sink:`, `run:`,
+    /// `keep:` and the like) only act on the lines they match.
+    pub fn styles(&self) -> bool {
+        !self.unstyled || self.redact.is_some()
+    }
+
+    /// `text` as the rule shows it: masked for a `redact:` rule, else unchanged
+    pub fn shown<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.redact {
+            Some(redact) => Cow::Owned(redact.apply(text)),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    /// The command of a `filter-cmd:` rule
+    pub fn filter_cmd(&self) -> Option<&str> {
+        self.filter_cmd.as_deref()
+    }
+
+    /// How many times the rule's regex matches `line`, leaving every other
+    /// directive aside. For timing rules one by one (`--profile-rules`).
+    pub fn count_matches(&self, line: &str) -> usize {
+        if let Some(wordlist) = &self.wordlist {
+            return wordlist.terms.find_iter(line).count();
+        }
+        self.regex.captures_iter(line).count()
+    }
+
+    fn new(regex: Regex, options: &RuleOptions) -> Self {
+        Rule {
+            regex,
+            // An `sgr:` rule may leave fg out, its escape replaces the colors anyway
+            fg_color: options.fg.unwrap_or(Color::White),
+            bg_color: options.bg,
+            fg_fallback: options.fg_fallback,
+            cols: options.cols,
+            rest: options.rest,
+            color_map: options.color_map.clone(),
+            cycle: options.cycle.clone(),
+            progress: options.progress,
+            alt_bg: options.alt_bg.clone(),
+            sgr: options.sgr.clone(),
+            styles: options.styles.clone(),
+            case: options.case,
+            format: options.format,
+            pad: options.pad,
+            filter_cmd: options.filter_cmd.clone(),
+            redact: options.redact,
+            unstyled: !options.has_color() && options.bg.is_none() && options.alt_bg.is_none(),
+            sink: options.sink.clone(),
+            run: options.run.clone(),
+            icon: options.icon.clone(),
+            guard_if: options.guard_if.clone(),
+            guard_unless: options.guard_unless.clone(),
+            guard_prog: options.guard_prog.clone(),
+            guard_args: options.guard_args.clone(),
+            min_cols: options.min_cols,
+            block: options.block,
+            needs: options
+                .needs
+                .clone()
+                .map(|needs| (needs, options.context_prev.unwrap_or(1))),
+            bell: options.bell,
+            fail_on: options.fail_on,
+            pass_on: options.pass_on,
+            severity: options.severity,
+            keep: options.keep,
+            drop: options.drop,
+            json: None,
+            logfmt: None,
+            wordlist: None,
+            whole_line: options.whole_line,
+            sample: options.sample,
+            global_count: options.global_count,
+            every: options.every,
+            dedup_window: options.dedup_window,
+            occurrence: options.occurrence,
+            range: options.range,
+            field: options.field,
+            field_matches: options.field_matches.clone(),
+            order: options.order,
+            aggregate: None,
+            section: None,
+            stream: StreamScope::Both,
+            flags: options.flags.clone(),
+            skip_quotes: false,
+            line: 0,
+        }
+    }
+
+    /// Whether `sample:` keeps the match of `text` at byte `start`. The choice is a
+    /// hash of both, so the same output is always sampled the same way.
+    pub(crate) fn sampled(&self, text: &str, start: usize) -> bool {
+        let Some(rate) = self.sample else {
+            return true;
+        };
+        let hash = fnv1a(text.bytes().chain(start.to_le_bytes()));
+        (hash as f64 / u64::MAX as f64) < rate
+    }
+
+    /// Whether matching matters even when nothing is colored
+    pub(crate) fn reports_matches(&self) -> bool {
+        self.bell
+            || self.fail_on
+            || self.pass_on
+            || self.severity.is_some()
+            || self.keep
+            || self.drop
+            || self.redact.is_some()
+            || self.sink.is_some()
+            || self.run.is_some()
+    }
+
+    /// Whether a `needs:` rule has its trigger among the lines before, the most
+    /// recent last
+    pub(crate) fn context_pass(&self, previous: &VecDeque<String>) -> bool {
+        self.needs.as_ref().is_none_or(|(needs, lines)| {
+            previous
+                .iter()
+                .rev()
+                .take(*lines)
+                .any(|line| needs.is_match(line))
+        })
+    }
+
+    /// Whether the `if:`/`unless:` guards let this rule run on `line`
+    pub(crate) fn guards_pass(&self, line: &str) -> bool {
+        self.guard_if.as_ref().is_none_or(|re| re.is_match(line))
+            && !self
+                .guard_unless
+                .as_ref()
+                .is_some_and(|re| re.is_match(line))
+    }
+
+    /// Foreground for the `ordinal`th (0-based) match on a line: the mapped color of
+    /// the first capture group (or the whole match) if a color map has it, else the
+    /// `cycle:` color for that position, else the `progress:` color of the
+    /// percentage, else the rule's own color
+    pub(crate) fn fg_for(&self, cap: &regex::Captures, ordinal: usize) -> Color {
+        self.color_map
+            .as_ref()
+            .and_then(|map| {
+                let key = cap.get(1).or_else(|| cap.get(0))?;
+                map.get(key.as_str()).copied()
+            })
+            .or_else(|| {
+                let progress = self.progress?;
+                let text = cap.get(1).or_else(|| cap.get(0))?.as_str();
+                Some(progress.color(parse_percentage(text)?))
+            })
+            .or_else(|| {
+                let cycle = self.cycle.as_ref()?;
+                cycle.get(ordinal % cycle.len()).copied()
+            })
+            .unwrap_or(self.fg_color)
+    }
+}
+
+fn parse_color(color: &str) -> Color {
+    parse_color_token(color).unwrap_or(Color::White) // Default to white
+}
+
+/// A color as rule files spell it: one of the 16 names (`red`), a 256-color
+/// palette index (`214`) or `#rrggbb`. Indices past the first 16 are written as
+/// truecolor with their xterm palette value, the escapes `colored` can produce.
+pub fn parse_color_token(color: &str) -> Option<Color> {
+    if let Some(hex) = color.strip_prefix('#') {
+        let channel = |idx: usize| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok();
+        return match hex.len() {
+            6 => Some(Color::TrueColor {
+                r: channel(0)?,
+                g: channel(2)?,
+                b: channel(4)?,
+            }),
+            _ => None,
+        };
+    }
+    match color.parse::<u8>() {
+        Ok(index) => Some(palette_color(index)),
+        Err(_) => parse_named_color(color),
+    }
+}
+
+/// The palette entry `color` stands for, when it is given as one (`38;5;N`)
+pub(crate) fn palette_index(color: Color) -> Option<u8> {
+    color.to_fg_str().strip_prefix("38;5;")?.parse().ok()
+}
+
+/// Entry `index` of the xterm 256-color palette
+pub(crate) fn palette_color(index: u8) -> Color {
+    const NAMED: [Color; 16] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::White,
+        Color::BrightBlack,
+        Color::BrightRed,
+        Color::BrightGreen,
+        Color::BrightYellow,
+        Color::BrightBlue,
+        Color::BrightMagenta,
+        Color::BrightCyan,
+        Color::BrightWhite,
+    ];
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        0..=15 => NAMED[index as usize],
+        16..=231 => {
+            let cube = index - 16;
+            Color::TrueColor {
+                r: CUBE_LEVELS[(cube / 36) as usize],
+                g: CUBE_LEVELS[(cube / 6 % 6) as usize],
+                b: CUBE_LEVELS[(cube % 6) as usize],
+            }
+        }
+        _ => {
+            let gray = 8 + 10 * (index - 232);
+            Color::TrueColor {
+                r: gray,
+                g: gray,
+                b: gray,
+            }
+        }
+    }
+}
+
+/// Terminal background the rules are picked for
+#[derive(Clone, Copy, PartialEq)]
+enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    /// `NSCWRS_THEME=dark|light`, else what `COLORFGBG` says about the background,
+    /// else dark
+    fn current() -> Self {
+        match std::env::var("NSCWRS_THEME").as_deref() {
+            Ok("dark") => return Theme::Dark,
+            Ok("light") => return Theme::Light,
+            _ => {}
+        }
+        if is_light_background(std::env::var("COLORFGBG").ok().as_deref()) {
+            Theme::Light
+        } else {
+            Theme::Dark
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+        }
+    }
+}
+
+/// `fg:dark=brightwhite,light=black`: the variant for `Theme::current`, or the
+/// other one if only that is given. A plain color is returned as it is.
+fn themed<'a>(value: &'a str, line_num: usize, diagnostics: &mut Vec<Diagnostic>) -> &'a str {
+    if !value.contains('=') {
+        return value;
+    }
+    let theme = Theme::current();
+    let (mut chosen, mut other) = (None, None);
+    for variant in value.split(',') {
+        match variant
+            .split_once('=')
+            .map(|(name, color)| (name.trim(), color.trim()))
+        {
+            Some((name, color)) if name == theme.name() => chosen = Some(color),
+            Some(("dark" | "light", color)) => other = Some(color),
+            _ => {
+                diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!(
+                        "Invalid themed color: {} (expected dark=<color>,light=<color>)",
+                        value
+                    ),
+                ));
+                return value;
+            }
+        }
+    }
+    chosen.or(other).unwrap_or(value)
+}
+
+/// Rule file spelling of `color`, the inverse of `parse_color_token`
+fn color_name(color: Color) -> String {
+    match color {
+        Color::Red => "red".to_string(),
+        Color::Blue => "blue".to_string(),
+        Color::Green => "green".to_string(),
+        Color::Yellow => "yellow".to_string(),
+        Color::Magenta => "magenta".to_string(),
+        Color::Cyan => "cyan".to_string(),
+        Color::White => "white".to_string(),
+        Color::Black => "black".to_string(),
+        Color::BrightRed => "brightred".to_string(),
+        Color::BrightBlue => "brightblue".to_string(),
+        Color::BrightGreen => "brightgreen".to_string(),
+        Color::BrightYellow => "brightyellow".to_string(),
+        Color::BrightMagenta => "brightmagenta".to_string(),
+        Color::BrightCyan => "brightcyan".to_string(),
+        Color::BrightWhite => "brightwhite".to_string(),
+        Color::BrightBlack => "brightblack".to_string(),
+        Color::TrueColor { r, g, b } => format!("#{:02x}{:02x}{:02x}", r, g, b),
+        // Later `colored` releases have palette colors of their own
+        #[allow(unreachable_patterns)]
+        other => palette_index(other).map_or_else(|| format!("{:?}", other), |i| i.to_string()),
+    }
+}
+
+/// One of the 16 standard terminal colors
+fn parse_named_color(color: &str) -> Option<Color> {
+    Some(match color.to_lowercase().as_str() {
+        "red" => Color::Red,
+        "blue" => Color::Blue,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        "black" => Color::Black,
+        "brightred" => Color::BrightRed,
+        "brightblue" => Color::BrightBlue,
+        "brightgreen" => Color::BrightGreen,
+        "brightyellow" => Color::BrightYellow,
+        "brightmagenta" => Color::BrightMagenta,
+        "brightcyan" => Color::BrightCyan,
+        "brightwhite" => Color::BrightWhite,
+        "brightblack" => Color::BrightBlack,
+        _ => return None,
+    })
+}
+
+/// Turn `\r\n` and lone `\r` (old Mac) line endings into `\n`. `str::lines` only
+/// knows the first, and a stray `\r` left on a line would end up in its regex.
+fn normalize_line_endings(content: String) -> String {
+    if !content.contains('\r') {
+        return content;
+    }
+    content.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Pull the rules out of a `# nscwrs-begin` ... `# nscwrs-end` block so they can live
+/// inside another file (e.g. a shell launcher). One leading `#` is removed from each
+/// line of the block, which lets the rules sit in a comment. Lines outside the block
+/// are blanked rather than dropped so diagnostics keep the original line numbers.
+fn extract_rules_section(content: &str, diagnostics: &mut Vec<Diagnostic>) -> Option<String> {
+    let begin = content
+        .lines()
+        .position(|line| line.trim() == SECTION_BEGIN)?;
+
+    let mut section = "\n".repeat(begin + 1);
+    let mut closed = false;
+    for line in content.lines().skip(begin + 1) {
+        let line = line.trim();
+        if line == SECTION_END {
+            closed = true;
+            break;
+        }
+        let line = line
+            .strip_prefix('#')
+            .map_or(line, |rest| rest.strip_prefix(' ').unwrap_or(rest));
+        section.push_str(line);
+        section.push('\n');
+    }
+    if !closed {
+        diagnostics.push(Diagnostic::new(
+            content.lines().count(),
+            format!(
+                "Missing '{}', read rules to the end of the file",
+                SECTION_END
+            ),
+        ));
+    }
+
+    Some(section)
+}
+
+pub fn load_color_rules(wrapper_path: &Path) -> RuleSet {
+    let content = fs::read_to_string(wrapper_path).unwrap_or_default();
+    parse_color_rules(&content, wrapper_path.parent().unwrap_or(Path::new(".")))
+}
+
+/// Rules from `content`, as if read from a file in `base_dir` (which relative
+/// `map-file:` paths are resolved against)
+pub fn parse_color_rules(content: &str, base_dir: &Path) -> RuleSet {
+    let content = normalize_line_endings(content.to_string());
+    let mut rule_set = RuleSet::default();
+    let content = extract_rules_section(&content, &mut rule_set.diagnostics).unwrap_or(content);
+    // Header waiting for its regex on the next line
+    let mut pending: Option<RuleOptions> = None;
+    let mut scope = Scope::default();
+    // `@define` name -> its directives, `use:` references already expanded
+    let mut defines: HashMap<String, String> = HashMap::new();
+    // Directives of the open `@push` scopes, outermost first, and their lines
+    let mut pushed: Vec<(String, usize)> = Vec::new();
+    let mut skip_quotes = false;
+    let max_errors = limit_from_env("NSCWRS_MAX_RULE_ERRORS", MAX_ERRORS);
+    let max_rules = limit_from_env("NSCWRS_MAX_RULES", MAX_RULES);
+
+    for (line_num, line) in content.lines().map(str::trim).enumerate() {
+        // `#!exec <program>` as the first line is `@exec` in shebang form
+        if line_num == 0
+            && let Some(program) = line.strip_prefix("#!exec ")
+        {
+            rule_set.exec = Some(program.trim().to_string());
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let limit = if rule_set.diagnostics.len() >= max_errors {
+            Some(format!("{} errors", max_errors))
+        } else if rule_set.rules.len() >= max_rules {
+            Some(format!("{} rules", max_rules))
+        } else {
+            None
+        };
+        if let Some(limit) = limit {
+            rule_set.diagnostics.push(Diagnostic::new(
+                line_num + 1,
+                format!(
+                    "Stopped reading after {}, this doesn't look like a rule file",
+                    limit
+                ),
+            ));
+            break;
+        }
+
+        if let Some(options) = pending.take() {
+            add_rule(&mut rule_set, &options, line, line_num + 1, scope, base_dir);
+        } else if let Some(program) = line.strip_prefix("@exec ") {
+            rule_set.exec = Some(program.trim().to_string());
+        } else if let Some(var) = line.strip_prefix("@env ") {
+            match var.trim().split_once('=') {
+                Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+                    rule_set.env.push((key.to_string(), value.to_string()));
+                }
+                _ => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @env: {} (expected @env KEY=VALUE)", var),
+                )),
+            }
+        } else if line == "@final" {
+            scope.final_pass = true;
+        } else if let Some(define) = line.strip_prefix("@define ") {
+            match define.trim().split_once(char::is_whitespace) {
+                Some((name, directives)) => {
+                    let directives = expand_uses(
+                        directives.trim(),
+                        &defines,
+                        line_num + 1,
+                        &mut rule_set.diagnostics,
+                    );
+                    defines.insert(name.to_string(), directives);
+                }
+                None => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!(
+                        "Invalid @define: {} (expected @define <name> <directives>)",
+                        define
+                    ),
+                )),
+            }
+        } else if let Some(directives) = line.strip_prefix("@push ") {
+            let directives = expand_uses(
+                directives.trim(),
+                &defines,
+                line_num + 1,
+                &mut rule_set.diagnostics,
+            );
+            // Reported once here, a bad directive is then left out of every rule
+            let valid: Vec<&str> = split_directives(&directives)
+                .into_iter()
+                .filter(|part| {
+                    let mut problems = Vec::new();
+                    parse_colors(part, line_num + 1, base_dir, &mut problems);
+                    let valid = problems.is_empty();
+                    rule_set.diagnostics.extend(problems);
+                    valid
+                })
+                .collect();
+            pushed.push((valid.join(","), line_num + 1));
+        } else if line == "@pop" {
+            if pushed.pop().is_none() {
+                rule_set
+                    .diagnostics
+                    .push(Diagnostic::new(line_num + 1, "@pop without @push"));
+            }
+        } else if let Some(color) = line.strip_prefix("@default-color ") {
+            match parse_color_token(color.trim()) {
+                Some(color) => rule_set.default_color = Some(color),
+                None => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @default-color: {}", color),
+                )),
+            }
+        } else if let Some(cascade) = line.strip_prefix("@cascade ") {
+            match cascade.trim() {
+                "first" => rule_set.last_wins = false,
+                "last" => rule_set.last_wins = true,
+                other => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @cascade: {} (first, last)", other),
+                )),
+            }
+        } else if let Some(preserve) = line.strip_prefix("@preserve-escapes ") {
+            match parse_bool(preserve.trim()) {
+                Some(preserve) => rule_set.preserve_escapes = preserve,
+                None => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @preserve-escapes: {} (true, false)", preserve),
+                )),
+            }
+        } else if let Some(skip) = line.strip_prefix("@skip-quotes ") {
+            match parse_bool(skip.trim()) {
+                Some(skip) => skip_quotes = skip,
+                None => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @skip-quotes: {} (true, false)", skip),
+                )),
+            }
+        } else if let Some(stream) = line.strip_prefix("@stream ") {
+            match StreamScope::parse(stream.trim()) {
+                Some(stream) => scope.stream = stream,
+                None => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @stream: {} (stdout, stderr, both)", stream),
+                )),
+            }
+        } else if let Some(trigger) = line.strip_prefix("@when ") {
+            match limited_regex(trigger.trim()).build() {
+                Ok(when) => {
+                    scope.section = Some(rule_set.sections.len());
+                    rule_set.sections.push(Section { when, until: None });
+                }
+                Err(err) => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @when regex: {} ({})", trigger, err),
+                )),
+            }
+        } else if let Some(reset) = line.strip_prefix("@until ") {
+            match (scope.section.take(), limited_regex(reset.trim()).build()) {
+                (Some(idx), Ok(until)) => rule_set.sections[idx].until = Some(until),
+                (None, _) => rule_set
+                    .diagnostics
+                    .push(Diagnostic::new(line_num + 1, "@until without @when")),
+                (_, Err(err)) => rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Invalid @until regex: {} ({})", reset, err),
+                )),
+            }
+        } else if let Some((color_def, pattern)) = split_header(line) {
+            let color_def =
+                expand_uses(color_def, &defines, line_num + 1, &mut rule_set.diagnostics);
+            let color_def = with_scopes(&pushed, &color_def);
+            let options = parse_colors(
+                &color_def,
+                line_num + 1,
+                base_dir,
+                &mut rule_set.diagnostics,
+            );
+            if !options.has_color() && !options.may_be_uncolored() {
+                rule_set.diagnostics.push(Diagnostic::new(
+                    line_num + 1,
+                    format!("Missing 'fg:' or 'sgr:' in color definition: {}", line),
+                ));
+            } else if pattern.trim().is_empty() {
+                pending = Some(options);
+            } else {
+                // `[fg:red] pattern` on a single line
+                let pattern = pattern.trim();
+                add_rule(
+                    &mut rule_set,
+                    &options,
+                    pattern,
+                    line_num + 1,
+                    scope,
+                    base_dir,
+                );
+            }
+        } else if let Some((fg, pattern)) = parse_shorthand(line) {
+            let scoped = with_scopes(&pushed, "");
+            let options = RuleOptions {
+                fg: Some(fg),
+                ..parse_colors(&scoped, line_num + 1, base_dir, &mut Vec::new())
+            };
+            add_rule(
+                &mut rule_set,
+                &options,
+                pattern,
+                line_num + 1,
+                scope,
+                base_dir,
+            );
+        }
+    }
+    for (_, line) in pushed {
+        rule_set
+            .diagnostics
+            .push(Diagnostic::new(line, "@push without @pop"));
+    }
+
+    // Stable, so rules with the same `order:` keep their file order
+    rule_set.rules.sort_by_key(|rule| rule.order);
+    // File-wide, wherever in the file it was written
+    for rule in &mut rule_set.rules {
+        rule.skip_quotes = skip_quotes;
+    }
+    if let Some(color) = rule_set.default_color {
+        rule_set.set_default_color(color);
+    }
+    rule_set
+}
+
+/// Limit from the environment variable `key`, `default` when unset or invalid.
+/// 0 means no limit.
+fn limit_from_env(key: &str, default: usize) -> usize {
+    match std::env::var(key).ok().and_then(|value| value.parse().ok()) {
+        Some(0) => usize::MAX,
+        Some(limit) => limit,
+        None => default,
+    }
+}
+
+/// Split `[directives] pattern` into its two parts. Brackets inside the header
+/// (e.g. `if:[0-9]+`) nest and `\]` is escaped, so regex values can use them.
+fn split_header(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let mut depth = 1;
+    let mut escaped = false;
+
+    for (idx, ch) in rest.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&rest[..idx], &rest[idx + 1..]));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Split a header into its directives. A comma only separates directives when a
+/// `key:` follows it, so values such as regexes or color lists can contain commas.
+fn split_directives(color_def: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+
+    for (idx, _) in color_def.match_indices(',') {
+        let next = color_def[idx + 1..].trim_start();
+        if next
+            .split_once(':')
+            .is_some_and(|(key, _)| is_directive_key(key))
+        {
+            parts.push(color_def[start..idx].trim());
+            start = idx + 1;
+        }
+    }
+    parts.push(color_def[start..].trim());
+
+    parts
+}
+
+/// File-level markers in effect for the rules being defined
+#[derive(Clone, Copy, Default)]
+struct Scope {
+    /// Open `@when` section
+    section: Option<usize>,
+    /// Last `@stream` marker
+    stream: StreamScope,
+    /// After `@final`, where only aggregate rules are allowed
+    final_pass: bool,
+}
+
+/// Compile `pattern` into a rule, reporting a bad regex against `line_num`
+fn add_rule(
+    rule_set: &mut RuleSet,
+    options: &RuleOptions,
+    pattern: &str,
+    line_num: usize,
+    scope: Scope,
+    base_dir: &Path,
+) {
+    let (aggregate, pattern) = match pattern.strip_prefix("max:") {
+        Some(_) if options.rank.is_some() => {
+            rule_set.diagnostics.push(Diagnostic::new(
+                line_num,
+                "rank: can't be used with max:, which is rank:top=1",
+            ));
+            return;
+        }
+        Some(pattern) => (Some(Aggregate::Max), pattern.trim()),
+        None => (options.rank.map(Aggregate::Top), pattern),
+    };
+    if aggregate.is_some() != scope.final_pass {
+        let message = match aggregate {
+            Some(_) => "max: and rank: rules must come after @final",
+            None => "Only max: and rank: rules are allowed after @final",
+        };
+        rule_set
+            .diagnostics
+            .push(Diagnostic::new(line_num, message));
+        return;
+    }
+
+    if let Some(spec) = pattern.strip_prefix("json:") {
+        match JsonField::parse(spec) {
+            Some(field) => {
+                let mut rule = Rule::new(field.locator(), options);
+                rule.json = Some(field);
+                rule.section = scope.section;
+                rule.stream = scope.stream;
+                rule.line = line_num;
+                rule_set.rules.push(rule);
+            }
+            None => rule_set.diagnostics.push(Diagnostic::new(
+                line_num,
+                format!(
+                    "Invalid json: pattern: {} (expected json:field=value)",
+                    spec
+                ),
+            )),
+        }
+        return;
+    }
+
+    if let Some(path) = pattern.strip_prefix("wordlist:") {
+        match Wordlist::load(&base_dir.join(path.trim()), options.flags.contains('i')) {
+            Ok(wordlist) => {
+                // Never used to match, it only stands in for the terms
+                let placeholder = Regex::new(r"[^\s\S]").expect("placeholder regex is valid");
+                let mut rule = Rule::new(placeholder, options);
+                rule.wordlist = Some(wordlist);
+                rule.section = scope.section;
+                rule.stream = scope.stream;
+                rule.line = line_num;
+                rule_set.rules.push(rule);
+            }
+            Err(message) => rule_set
+                .diagnostics
+                .push(Diagnostic::new(line_num, message)),
+        }
+        return;
+    }
+
+    let logfmt = match pattern.strip_prefix("logfmt-key:") {
+        Some(spec) => Some((spec, true)),
+        None => pattern.strip_prefix("logfmt:").map(|spec| (spec, false)),
+    };
+    if let Some((spec, color_key)) = logfmt {
+        match LogfmtField::parse(spec, color_key) {
+            Some(field) => {
+                let mut rule = Rule::new(field.locator(), options);
+                rule.logfmt = Some(field);
+                rule.section = scope.section;
+                rule.stream = scope.stream;
+                rule.line = line_num;
+                rule_set.rules.push(rule);
+            }
+            None => rule_set.diagnostics.push(Diagnostic::new(
+                line_num,
+                format!(
+                    "Invalid logfmt pattern: {} (expected logfmt:key=value, logfmt:key or logfmt:*)",
+                    pattern
+                ),
+            )),
+        }
+        return;
+    }
+
+    // `and:<A>&<B>`: A is colored, on lines where B matches too, like an `if:` guard
+    let (pattern, also) = match pattern.strip_prefix("and:") {
+        Some(spec) => match spec.split_once('&') {
+            Some(_) if options.guard_if.is_some() => {
+                rule_set.diagnostics.push(Diagnostic::new(
+                    line_num,
+                    "An and: pattern can't be combined with if:",
+                ));
+                return;
+            }
+            Some((pattern, also)) => match build_regex(also, &options.flags) {
+                Ok(re) => (pattern, Some(re)),
+                Err(err) => {
+                    rule_set.diagnostics.push(Diagnostic::new(
+                        line_num,
+                        format!("Invalid and: regex: {} ({})", also, err),
+                    ));
+                    return;
+                }
+            },
+            None => {
+                rule_set.diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!(
+                        "Invalid and: pattern: {} (expected and:<regex>&<regex>)",
+                        spec
+                    ),
+                ));
+                return;
+            }
+        },
+        None => (pattern, None),
+    };
+    let pattern = pattern_macro(pattern).unwrap_or(pattern);
+    match build_regex(pattern, &options.flags) {
+        Ok(re) => {
+            let mut rule = Rule::new(re, options);
+            if also.is_some() {
+                rule.guard_if = also;
+            }
+            rule.aggregate = aggregate;
+            rule.section = scope.section;
+            rule.stream = scope.stream;
+            rule.line = line_num;
+            rule_set.rules.push(rule);
+        }
+        Err(err) => rule_set.diagnostics.push(Diagnostic::new(
+            line_num,
+            format!("Invalid regex: {} ({})", pattern, err),
+        )),
+    }
+}
+
+/// `red: ^ERROR`, shorthand for a `[fg:red]` rule. Only the 16 named colors are
+/// accepted so arbitrary `word: text` lines aren't mistaken for rules.
+fn parse_shorthand(line: &str) -> Option<(Color, &str)> {
+    let (name, pattern) = line.split_once(':')?;
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return None;
+    }
+    Some((parse_named_color(name.trim())?, pattern))
+}
+
+/// `color_def` with the directives of the open `@push` scopes beneath it, inner
+/// scopes over outer ones
+fn with_scopes(pushed: &[(String, usize)], color_def: &str) -> String {
+    pushed
+        .iter()
+        .map(|(directives, _)| directives.as_str())
+        .chain([color_def])
+        .filter(|directives| !directives.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Replace each `use:<name>` directive with the directives given to `@define <name>`.
+/// Directives after the `use:` override what it brought in. An `extends:<name>`
+/// brings them in beneath all of the header's own, wherever it is written.
+fn expand_uses(
+    color_def: &str,
+    defines: &HashMap<String, String>,
+    line_num: usize,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> String {
+    let mut bases = Vec::new();
+    let mut parts = Vec::new();
+    for part in split_directives(color_def) {
+        let (name, expanded) = match (part.strip_prefix("use:"), part.strip_prefix("extends:")) {
+            (Some(name), _) => (name, &mut parts),
+            (None, Some(name)) => (name, &mut bases),
+            (None, None) => {
+                parts.push(part);
+                continue;
+            }
+        };
+        match defines.get(name) {
+            Some(directives) => expanded.push(directives.as_str()),
+            None => diagnostics.push(Diagnostic::new(
+                line_num,
+                format!("Unknown color variable: {}", name),
+            )),
+        }
+    }
+    bases.extend(parts);
+    bases.join(",")
+}
+
+/// Whether `key` can name a directive (`fg`, `map-file`)
+fn is_directive_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+}
+
+fn parse_colors(
+    color_def: &str,
+    line_num: usize,
+    base_dir: &Path,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> RuleOptions {
+    let mut options = RuleOptions::default();
+
+    for part in split_directives(color_def) {
+        if let Some(fg_color) = part.strip_prefix("fg:") {
+            let fg_color = themed(fg_color, line_num, diagnostics);
+            options.fg = Some(parse_color(fg_color));
+            options.fg_fallback = parse_color_token(fg_color).is_none();
+        } else if let Some(bg_color) = part.strip_prefix("bg:") {
+            options.bg = Some(parse_color(themed(bg_color, line_num, diagnostics)));
+        } else if let Some(styles) = part.strip_prefix("style:") {
+            options.styles = parse_styles(styles, line_num, diagnostics);
+        } else if let Some(range) = part.strip_prefix("cols:") {
+            match parse_column_range(range) {
+                Some(cols) => options.cols = Some(cols),
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid column range: {}", range),
+                )),
+            }
+        } else if let Some(rest) = part.strip_prefix("rest:") {
+            match rest {
+                "true" | "include" => options.rest = Some(RestOfLine::Include),
+                "after" => options.rest = Some(RestOfLine::After),
+                "false" => options.rest = None,
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid rest mode: {}", rest),
+                )),
+            }
+        } else if let Some(flags) = part.strip_prefix("flags:") {
+            for flag in flags.chars() {
+                if REGEX_FLAGS.contains(flag) {
+                    options.flags.push(flag);
+                } else {
+                    diagnostics.push(Diagnostic::new(
+                        line_num,
+                        format!("Unknown regex flag: {}", flag),
+                    ));
+                }
+            }
+        } else if let Some(case) = part.strip_prefix("case:") {
+            match CaseTransform::parse(case) {
+                Some(case) => options.case = Some(case),
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid case: {} (upper, lower, title)", case),
+                )),
+            }
+        } else if let Some(format) = part.strip_prefix("format:") {
+            match NumberFormat::parse(format) {
+                Some(format) => options.format = Some(format),
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid format: {} (thousands)", format),
+                )),
+            }
+        } else if let Some(cmd) = part.strip_prefix("filter-cmd:") {
+            match cmd.trim() {
+                "" => diagnostics.push(Diagnostic::new(line_num, "Empty filter-cmd:")),
+                cmd => options.filter_cmd = Some(cmd.to_string()),
+            }
+        } else if let Some(pad) = part.strip_prefix("pad:") {
+            match Padding::parse(pad) {
+                Some(pad) => options.pad = Some(pad),
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!(
+                        "Invalid pad: {} (a width from 1 to {}, or >width to right-align)",
+                        pad, MAX_PAD
+                    ),
+                )),
+            }
+        } else if let Some(block) = part.strip_prefix("block:") {
+            match block {
+                "first" => options.block = Some(BlockPosition::First),
+                "rest" => options.block = Some(BlockPosition::Rest),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid block: {} (first, rest)", block),
+                )),
+            }
+        } else if let Some(guard) = part.strip_prefix("if:") {
+            options.guard_if = compile_option_regex("if", guard, line_num, diagnostics);
+        } else if let Some(guard) = part.strip_prefix("unless:") {
+            options.guard_unless = compile_option_regex("unless", guard, line_num, diagnostics);
+        } else if let Some(needs) = part.strip_prefix("needs:") {
+            options.needs = compile_option_regex("needs", needs, line_num, diagnostics);
+        } else if let Some(lines) = part.strip_prefix("context-prev:") {
+            match lines.parse::<usize>() {
+                Ok(lines) if lines > 0 => options.context_prev = Some(lines),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!(
+                        "Invalid context-prev: {} (expected a line count >= 1)",
+                        lines
+                    ),
+                )),
+            }
+        } else if let Some(cols) = part.strip_prefix("min-cols:") {
+            match cols.parse::<usize>() {
+                Ok(cols) if cols > 0 => options.min_cols = Some(cols),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid min-cols: {} (expected a width >= 1)", cols),
+                )),
+            }
+        } else if let Some(guard) = part.strip_prefix("prog:") {
+            options.guard_prog = compile_option_regex("prog", guard, line_num, diagnostics);
+        } else if let Some(guard) = part.strip_prefix("arg:") {
+            options.guard_args = compile_option_regex("arg", guard, line_num, diagnostics);
+        } else if let Some(redact) = part.strip_prefix("redact:") {
+            match Redact::parse(redact) {
+                Some(redact) => options.redact = Some(redact),
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid redact value: {} (true, length)", redact),
+                )),
+            }
+        } else if let Some(bell) = part.strip_prefix("bell:") {
+            match parse_bool(bell) {
+                Some(bell) => options.bell = bell,
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid bell value: {} (true, false)", bell),
+                )),
+            }
+        } else if let Some(whole_line) = part.strip_prefix("line:") {
+            match parse_bool(whole_line) {
+                Some(whole_line) => options.whole_line = whole_line,
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid line value: {} (true, false)", whole_line),
+                )),
+            }
+        } else if let Some(order) = part.strip_prefix("order:") {
+            match order.parse() {
+                Ok(order) => options.order = order,
+                Err(_) => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid order: {} (expected an integer)", order),
+                )),
+            }
+        } else if let Some(rate) = part.strip_prefix("sample:") {
+            match rate.parse::<f64>() {
+                Ok(rate) if rate > 0.0 && rate <= 1.0 => options.sample = Some(rate),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid sample rate: {} (expected 0 < rate <= 1)", rate),
+                )),
+            }
+        } else if let Some(limit) = part.strip_prefix("global-count:") {
+            match limit.parse::<usize>() {
+                Ok(limit) if limit > 0 => options.global_count = Some(limit),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid global-count: {} (expected a count >= 1)", limit),
+                )),
+            }
+        } else if let Some(window) = part.strip_prefix("dedup-window:") {
+            match window.parse::<usize>() {
+                Ok(window) if window > 0 => options.dedup_window = Some(window),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid dedup-window: {} (expected a count >= 1)", window),
+                )),
+            }
+        } else if let Some(every) = part.strip_prefix("every:") {
+            match every.parse::<usize>() {
+                Ok(every) if every > 0 => options.every = Some(every),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid every: {} (expected a count >= 1)", every),
+                )),
+            }
+        } else if let Some(occurrence) = part.strip_prefix("occurrence:") {
+            match occurrence.parse::<isize>() {
+                Ok(occurrence) if occurrence != 0 => options.occurrence = Some(occurrence),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!(
+                        "Invalid occurrence: {} (1 is the first match, -1 the last)",
+                        occurrence
+                    ),
+                )),
+            }
+        } else if let Some(rank) = part.strip_prefix("rank:") {
+            match rank
+                .strip_prefix("top=")
+                .and_then(|count| count.parse::<usize>().ok())
+            {
+                Some(count) if count > 0 => options.rank = Some(count),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid rank: {} (expected top=N, N >= 1)", rank),
+                )),
+            }
+        } else if let Some(range) = part.strip_prefix("range:") {
+            match parse_column_range(range) {
+                Some(range) => options.range = Some(range),
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid range: {} (expected X-Y, e.g. 3-6)", range),
+                )),
+            }
+        } else if let Some(field) = part.strip_prefix("field:") {
+            match field.parse::<isize>() {
+                Ok(field) if field != 0 => options.field = Some(field),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!(
+                        "Invalid field: {} (1 is the first field, -1 the last)",
+                        field
+                    ),
+                )),
+            }
+        } else if let Some(valid) = part.strip_prefix("matches:") {
+            options.field_matches = compile_option_regex("matches", valid, line_num, diagnostics);
+        } else if let Some(colors) = part.strip_prefix("cycle:") {
+            options.cycle = parse_color_list("cycle", colors, line_num, diagnostics);
+        } else if let Some(progress) = part.strip_prefix("progress:") {
+            match progress {
+                "true" => options.progress = Some(Progress::RedToGreen),
+                "reverse" => options.progress = Some(Progress::GreenToRed),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid progress: {} (true, reverse)", progress),
+                )),
+            }
+        } else if let Some(colors) = part.strip_prefix("alt-bg:") {
+            options.alt_bg = parse_color_list("alt-bg", colors, line_num, diagnostics);
+        } else if let Some(fail_on) = part.strip_prefix("fail-on:") {
+            match parse_bool(fail_on) {
+                Some(fail_on) => options.fail_on = fail_on,
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid fail-on value: {} (true, false)", fail_on),
+                )),
+            }
+        } else if let Some(pass_on) = part.strip_prefix("pass-on:") {
+            match parse_bool(pass_on) {
+                Some(pass_on) => options.pass_on = pass_on,
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid pass-on value: {} (true, false)", pass_on),
+                )),
+            }
+        } else if let Some(severity) = part.strip_prefix("severity:") {
+            match severity {
+                "warn" | "warning" => options.severity = Some(Severity::Warn),
+                "error" => options.severity = Some(Severity::Error),
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid severity: {} (warn, error)", severity),
+                )),
+            }
+        } else if let Some(keep) = part.strip_prefix("keep:") {
+            match parse_bool(keep) {
+                Some(keep) => options.keep = keep,
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid keep value: {} (true, false)", keep),
+                )),
+            }
+        } else if let Some(drop) = part.strip_prefix("drop:") {
+            match parse_bool(drop) {
+                Some(drop) => options.drop = drop,
+                None => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Invalid drop value: {} (true, false)", drop),
+                )),
+            }
+        } else if let Some(params) = part.strip_prefix("sgr:") {
+            options.sgr = parse_sgr(params, line_num, diagnostics);
+        } else if let Some(sink) = part.strip_prefix("sink:") {
+            match sink.trim() {
+                "" => diagnostics.push(Diagnostic::new(line_num, "Missing file for sink:")),
+                sink => options.sink = Some(PathBuf::from(sink)),
+            }
+        } else if let Some(icon) = part.strip_prefix("icon:") {
+            match icon.trim() {
+                "" => diagnostics.push(Diagnostic::new(line_num, "Missing text for icon:")),
+                icon => options.icon = Some(icon.to_string()),
+            }
+        } else if let Some(run) = part.strip_prefix("run:") {
+            match run.trim() {
+                "" => diagnostics.push(Diagnostic::new(line_num, "Missing command for run:")),
+                run => options.run = Some(run.to_string()),
+            }
+        } else if let Some(map_file) = part.strip_prefix("map-file:") {
+            match load_color_map(&base_dir.join(map_file), line_num, diagnostics) {
+                Ok(map) => options.color_map = Some(map),
+                Err(err) => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!("Could not read color map {} ({})", map_file, err),
+                )),
+            }
+        } else if let Some(spec) = part.strip_prefix("csv-map:") {
+            // The file name may hold a `:` itself (`C:\...`), the columns can't
+            let mut fields = spec.rsplitn(3, ':');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(color_col), Some(key_col), Some(csv_file)) if !csv_file.is_empty() => {
+                    match load_csv_color_map(
+                        &base_dir.join(csv_file),
+                        key_col,
+                        color_col,
+                        line_num,
+                        diagnostics,
+                    ) {
+                        Ok(map) => options.color_map = Some(map),
+                        Err(err) => diagnostics.push(Diagnostic::new(
+                            line_num,
+                            format!("Could not read color map {} ({})", csv_file, err),
+                        )),
+                    }
+                }
+                _ => diagnostics.push(Diagnostic::new(
+                    line_num,
+                    format!(
+                        "Invalid csv-map: {} (expected FILE:KEY-COLUMN:COLOR-COLUMN)",
+                        spec
+                    ),
+                )),
+            }
+        } else if part
+            .split_once(':')
+            .is_some_and(|(key, _)| is_directive_key(key))
+        {
+            // A misspelled or unsupported directive. Text that isn't `key:value`,
+            // such as a log line's timestamp, is left to the missing-color check.
+            diagnostics.push(Diagnostic::new(
+                line_num,
+                format!("Unknown directive: {}", part),
+            ));
+        }
+    }
+
+    if options.field_matches.is_some() && options.field.is_none() {
+        diagnostics.push(Diagnostic::new(
+            line_num,
+            "matches: without field: has no effect",
+        ));
+    }
+    if options.context_prev.is_some() && options.needs.is_none() {
+        diagnostics.push(Diagnostic::new(
+            line_num,
+            "context-prev: without needs: has no effect",
+        ));
+    }
+
+    options
+}
+
+/// `red,green,blue` for `directive`, unknown names are reported and left out
+fn parse_color_list(
+    directive: &str,
+    colors: &str,
+    line_num: usize,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Vec<Color>> {
+    let mut cycle = Vec::new();
+    for name in colors.split(',').map(str::trim) {
+        match parse_color_token(name) {
+            Some(color) => cycle.push(color),
+            None => diagnostics.push(Diagnostic::new(
+                line_num,
+                format!("Invalid {}: color: {}", directive, name),
+            )),
+        }
+    }
+    (!cycle.is_empty()).then_some(cycle)
+}
+
+/// `bold,underline` for `style:`, unknown names are reported and left out
+fn parse_styles(styles: &str, line_num: usize, diagnostics: &mut Vec<Diagnostic>) -> Vec<Styles> {
+    let mut parsed = Vec::new();
+    for name in styles.split(',').map(str::trim) {
+        match STYLE_NAMES.iter().find(|(known, _)| *known == name) {
+            Some(&(_, style)) => parsed.push(style),
+            None => diagnostics.push(Diagnostic::new(
+                line_num,
+                format!(
+                    "Invalid style: {} (bold, dim, italic, underline, blink, reverse, strikethrough)",
+                    name
+                ),
+            )),
+        }
+    }
+    parsed
+}
+
+/// Names accepted by `style:`
+const STYLE_NAMES: &[(&str, Styles)] = &[
+    ("bold", Styles::Bold),
+    ("dim", Styles::Dimmed),
+    ("italic", Styles::Italic),
+    ("underline", Styles::Underline),
+    ("blink", Styles::Blink),
+    ("reverse", Styles::Reversed),
+    ("strikethrough", Styles::Strikethrough),
+];
+
+/// `style:` name of `style`, as `--dump-rules` writes it
+fn style_name(style: Styles) -> &'static str {
+    STYLE_NAMES
+        .iter()
+        .find(|(_, known)| *known == style)
+        .map_or("?", |(name, _)| name)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Compile the regex value of a directive such as `if:`
+fn compile_option_regex(
+    directive: &str,
+    pattern: &str,
+    line_num: usize,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Regex> {
+    limited_regex(pattern)
+        .build()
+        .map_err(|err| {
+            diagnostics.push(Diagnostic::new(
+                line_num,
+                format!("Invalid {}: regex: {} ({})", directive, pattern, err),
+            ))
+        })
+        .ok()
+}
+
+/// Ready-made patterns usable in place of a regex (`[fg:cyan] @url`). A pattern
+/// that only looks like one (`@user`) is taken as a regex.
+const PATTERN_MACROS: &[(&str, &str)] = &[
+    // Trailing punctuation is left out so `(see https://x.org/a).` stops at `a`
+    (
+        "@url",
+        r#"\b(?:https?|ftp|file)://[^\s<>"'`]*[^\s<>"'`.,;:!?)\]}]"#,
+    ),
+    (
+        "@ipv4",
+        r"\b(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\b",
+    ),
+    // Longest forms first, the regex crate takes the first alternative that matches.
+    // `::1` style addresses must not follow a word, so `std::cout` isn't one.
+    (
+        "@ipv6",
+        r"\b(?:(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}|[0-9A-Fa-f]{1,4}:(?::[0-9A-Fa-f]{1,4}){1,6}|(?:[0-9A-Fa-f]{1,4}:){1,2}(?::[0-9A-Fa-f]{1,4}){1,5}|(?:[0-9A-Fa-f]{1,4}:){1,3}(?::[0-9A-Fa-f]{1,4}){1,4}|(?:[0-9A-Fa-f]{1,4}:){1,4}(?::[0-9A-Fa-f]{1,4}){1,3}|(?:[0-9A-Fa-f]{1,4}:){1,5}(?::[0-9A-Fa-f]{1,4}){1,2}|(?:[0-9A-Fa-f]{1,4}:){1,6}:[0-9A-Fa-f]{1,4})\b|\b(?:[0-9A-Fa-f]{1,4}:){2,7}:|\B::[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4}){0,6}\b",
+    ),
+    // Absolute, `~/`, `./` and `../` paths, not `a/b` inside words
+    ("@path", r"\B(?:~|\.{1,2})?/[\w.+@-]+(?:/[\w.+@-]+)*/?"),
+    (
+        "@email",
+        r"\b[\w.%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}\b",
+    ),
+    (
+        "@uuid",
+        r"\b[0-9A-Fa-f]{8}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{12}\b",
+    ),
+    // Secrets, meant for `redact:`
+    (
+        "@aws-key",
+        r"\b(?:AKIA|ASIA|AGPA|AIDA|AROA|AIPA|ANPA|ANVA)[0-9A-Z]{16}\b",
+    ),
+    // Header and payload are base64url JSON objects, so both start with `eyJ`
+    ("@jwt", r"\beyJ[\w-]+\.eyJ[\w-]+\.[\w-]*"),
+    ("@bearer", r"\b[Bb]earer\s+[\w.~+/-]+=*"),
+];
+
+/// Regex behind a `PATTERN_MACROS` name
+fn pattern_macro(pattern: &str) -> Option<&'static str> {
+    PATTERN_MACROS
+        .iter()
+        .find(|(name, _)| *name == pattern)
+        .map(|(_, regex)| *regex)
+}
+
+/// Letters accepted by `flags:`
+const REGEX_FLAGS: &str = "iuUxsm";
+
+/// Compile a rule pattern with its `flags:` letters mapped onto `RegexBuilder`:
+/// `i` case-insensitive, `u` Unicode (already the default, so `\p{Han}` and friends
+/// work without it), `U` swap greedy and lazy, `x` ignore whitespace, `s` let `.`
+/// match newlines, `m` multi-line anchors.
+fn build_regex(pattern: &str, flags: &str) -> Result<Regex, regex::Error> {
+    limited_regex(pattern)
+        .case_insensitive(flags.contains('i'))
+        .unicode(true)
+        .swap_greed(flags.contains('U'))
+        .ignore_whitespace(flags.contains('x'))
+        .dot_matches_new_line(flags.contains('s'))
+        .multi_line(flags.contains('m'))
+        .build()
+}
+
+/// Validate a `;`-separated SGR parameter list, dropping anything that isn't a number
+fn parse_sgr(params: &str, line_num: usize, diagnostics: &mut Vec<Diagnostic>) -> Option<String> {
+    let mut valid = Vec::new();
+    for param in params.split(';').map(str::trim) {
+        if param.parse::<u8>().is_ok() {
+            valid.push(param);
+        } else {
+            diagnostics.push(Diagnostic::new(
+                line_num,
+                format!("Ignoring non-numeric SGR parameter: {:?}", param),
+            ));
+        }
+    }
+    (!valid.is_empty()).then(|| valid.join(";"))
+}
+
+/// Read `key=color` lines for the `map-file:` directive. Paths are relative to
+/// the rule file, blank lines and `#` comments are skipped. Bad entries are
+/// reported against `rule_line`, the rule that referenced the map.
+fn load_color_map(
+    path: &Path,
+    rule_line: usize,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> io::Result<HashMap<String, Color>> {
+    let content = normalize_line_endings(fs::read_to_string(path)?);
+    let mut map = HashMap::new();
+
+    for (line_num, line) in content.lines().map(str::trim).enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, color)) => {
+                map.insert(key.trim().to_string(), parse_color(color.trim()));
+            }
+            None => diagnostics.push(Diagnostic::new(
+                rule_line,
+                format!(
+                    "Expected key=color on line {} of {}: {}",
+                    line_num + 1,
+                    path.display(),
+                    line
+                ),
+            )),
+        }
+    }
+
+    Ok(map)
+}
+
+/// Read a color map for `csv-map:` from two columns of a CSV file, named by its
+/// header row. Rows with a color that isn't one are reported and left out, so
+/// their keys keep the rule's `fg`.
+fn load_csv_color_map(
+    path: &Path,
+    key_col: &str,
+    color_col: &str,
+    rule_line: usize,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> io::Result<HashMap<String, Color>> {
+    let content = normalize_line_endings(fs::read_to_string(path)?);
+    let mut rows = content.lines().filter(|line| !line.trim().is_empty());
+    let header = split_csv_row(rows.next().unwrap_or_default());
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|field| field == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no column named {}", name),
+                )
+            })
+    };
+    let (key_idx, color_idx) = (column(key_col)?, column(color_col)?);
+
+    let mut map = HashMap::new();
+    for (row_num, row) in rows.enumerate() {
+        let fields = split_csv_row(row);
+        let (Some(key), Some(color)) = (fields.get(key_idx), fields.get(color_idx)) else {
+            continue; // A short row has no value to map
+        };
+        match parse_color_token(color) {
+            Some(color) => {
+                map.insert(key.clone(), color);
+            }
+            None => diagnostics.push(Diagnostic::new(
+                rule_line,
+                format!(
+                    "Invalid color on row {} of {}: {}",
+                    row_num + 2,
+                    path.display(),
+                    color
+                ),
+            )),
+        }
+    }
+
+    Ok(map)
+}
+
+/// The fields of a CSV row, trimmed. A field may be quoted to hold commas, with
+/// `""` for a quote inside it.
+fn split_csv_row(row: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            _ => field.push(ch),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+/// Parse `X-Y` (both 1-based, inclusive). An open end (`X-`) extends to the end of
+/// the line, or to the last match for `range:`.
+fn parse_column_range(range: &str) -> Option<(usize, usize)> {
+    let (first, last) = range.split_once('-')?;
+    let first: usize = first.trim().parse().ok()?;
+    let last = match last.trim() {
+        "" => usize::MAX,
+        last => last.parse().ok()?,
+    };
+    (first >= 1 && first <= last).then_some((first, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rules: &str) -> RuleSet {
+        parse_color_rules(rules, Path::new("."))
+    }
+
+    /// The messages of the problems found in the rules
+    fn problems(rule_set: &RuleSet) -> Vec<&str> {
+        rule_set
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn embedded_block_is_the_only_rules() {
+        let rule_set = parse(
+            "#!/bin/sh\n[fg:blue] ignored\n# nscwrs-begin\n# [fg:red] error\n# nscwrs-end\necho hi\n",
+        );
+        assert!(problems(&rule_set).is_empty());
+        assert_eq!(rule_set.rules().len(), 1);
+        assert_eq!(rule_set.rules()[0].pattern(), "error");
+    }
+
+    #[test]
+    fn every_problem_is_reported_with_its_line() {
+        let rule_set = parse("[fg:red] (\n[fg:red] ok\n[bg:blue] x\n");
+        let lines: Vec<usize> = rule_set.diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [1, 3]);
+        // The good rule is still used
+        assert_eq!(rule_set.rules().len(), 1);
+        assert_eq!(rule_set.rules()[0].pattern(), "ok");
+    }
+
+    #[test]
+    fn non_numeric_sgr_parameters_are_left_out() {
+        let rule_set = parse("[sgr:1;x] error\n");
+        assert_eq!(
+            problems(&rule_set),
+            ["Ignoring non-numeric SGR parameter: \"x\""]
+        );
+        assert_eq!(rule_set.rules()[0].sgr.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn shorthand_takes_only_named_colors() {
+        // Not a color, so not a rule either
+        let rule_set = parse("nocolor: x\n");
+        assert!(rule_set.rules().is_empty());
+        assert_eq!(parse("red: x\n").rules()[0].pattern(), "x");
+    }
+
+    #[test]
+    fn unknown_directives_are_reported() {
+        let rule_set = parse("[fg:red] ok\n[fg:red,bogus:1] error\n[2024-01-01 12:00:00] x\n");
+        assert_eq!(
+            problems(&rule_set),
+            [
+                "Unknown directive: bogus:1",
+                "Missing 'fg:' or 'sgr:' in color definition: [2024-01-01 12:00:00] x"
+            ]
+        );
+        assert_eq!(rule_set.diagnostics[0].line, 2);
+        // The rule itself is kept, as with other bad directives
+        assert_eq!(rule_set.rules().len(), 2);
+        assert_eq!(rule_set.rules()[1].pattern(), "error");
+    }
+
+    #[test]
+    fn dump_lists_each_rule_with_its_directives() {
+        let rule_set = parse("@exec make\n[fg:red,bg:blue,flags:i] error\n[fg:green,cols:3-] ok\n");
+        assert_eq!(
+            rule_set.to_string(),
+            "@exec make\n\
+             rule 0 (line 2): /error/ fg:red bg:blue flags:i\n\
+             rule 1 (line 3): /ok/ fg:green cols:3-\n"
+        );
+    }
+
+    #[test]
+    fn crlf_and_lone_cr_line_endings_stay_out_of_the_patterns() {
+        let path = std::env::temp_dir().join(format!("nscwrs-{}-crlf.rules", std::process::id()));
+        fs::write(
+            &path,
+            "[fg:red]\r\nerror$\r\n[fg:blue] warn$\r[fg:green] ok\r",
+        )
+        .unwrap();
+        let rule_set = load_color_rules(&path);
+        let _ = fs::remove_file(&path);
+        assert!(problems(&rule_set).is_empty());
+        let patterns: Vec<&str> = rule_set.rules().iter().map(|rule| rule.pattern()).collect();
+        assert_eq!(patterns, ["error$", "warn$", "ok"]);
+        assert!(rule_set.rules()[0].regex.is_match("an error"));
+    }
+
+    #[test]
+    fn fnv1a_gives_the_published_hashes() {
+        assert_eq!(fnv1a("".bytes()), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a("a".bytes()), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a("foobar".bytes()), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn defines_are_pulled_in_with_use() {
+        let rule_set = parse(
+            "@define danger fg:brightred,style:bold\n[use:danger] ^ERROR\n[use:danger,fg:blue] FATAL\n[use:nope,fg:red] x\n",
+        );
+        let dump = rule_set.to_string();
+        let rules: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            rules[0],
+            "rule 0 (line 2): /^ERROR/ fg:brightred style:bold"
+        );
+        // Directives after `use:` override the definition's
+        assert_eq!(rules[1], "rule 1 (line 3): /FATAL/ fg:blue style:bold");
+        assert_eq!(problems(&rule_set), ["Unknown color variable: nope"]);
+        assert_eq!(rule_set.diagnostics[0].line, 4);
+    }
+
+    #[test]
+    fn style_attributes_are_listed_and_unknown_ones_reported() {
+        let rule_set = parse("[fg:red,style:bold,underline,wobble] x\n");
+        assert!(
+            rule_set
+                .to_string()
+                .starts_with("rule 0 (line 1): /x/ fg:red style:bold,underline\n")
+        );
+        assert_eq!(
+            problems(&rule_set),
+            ["Invalid style: wobble (bold, dim, italic, underline, blink, reverse, strikethrough)"]
+        );
+    }
+
+    #[test]
+    fn unknown_and_default_colors_get_the_fallback() {
+        let fgs = |rule_set: &RuleSet| -> Vec<String> {
+            rule_set
+                .rules()
+                .iter()
+                .map(|rule| color_name(rule.fg_color))
+                .collect()
+        };
+        let rules = "[fg:nosuch] a\n[fg:default] b\n[fg:red] c\n";
+        assert_eq!(fgs(&parse(rules)), ["white", "white", "red"]);
+        let mut rule_set = parse(&format!("@default-color cyan\n{}", rules));
+        assert_eq!(fgs(&rule_set), ["cyan", "cyan", "red"]);
+        // `--default-color` over `@default-color`
+        rule_set.set_default_color(Color::Black);
+        assert_eq!(fgs(&rule_set), ["black", "black", "red"]);
+    }
+
+    #[test]
+    fn low_contrast_pairs_are_warned_about() {
+        let rule_set = parse(
+            "[fg:red,bg:red] same\n[fg:red,bg:white] fine\n[fg:white,alt-bg:black,brightwhite] alt\n[sgr:31;41] raw\n",
+        );
+        let warnings = rule_set.contrast_warnings();
+        let lines: Vec<usize> = warnings.iter().map(|warning| warning.line).collect();
+        assert_eq!(lines, [1, 3]);
+        assert_eq!(
+            warnings[0].message,
+            "fg:red on bg:red is hard to read (contrast 1.0:1)"
+        );
+        assert!(
+            warnings[1]
+                .message
+                .starts_with("fg:white on bg:brightwhite")
+        );
+    }
+
+    #[test]
+    fn palette_indices_and_hex_colors_are_colors() {
+        assert_eq!(parse_color_token("9"), Some(Color::BrightRed));
+        assert_eq!(
+            parse_color_token("214"),
+            Some(Color::TrueColor {
+                r: 255,
+                g: 175,
+                b: 0
+            })
+        );
+        assert_eq!(
+            parse_color_token("#FF8800"),
+            Some(Color::TrueColor {
+                r: 255,
+                g: 136,
+                b: 0
+            })
+        );
+        for invalid in ["256", "#12345", "#gg0000", "-1"] {
+            assert_eq!(parse_color_token(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn themed_colors_take_only_dark_and_light() {
+        let rule_set = parse("[fg:dim=red,light=black] a\n");
+        assert_eq!(
+            problems(&rule_set),
+            ["Invalid themed color: dim=red,light=black (expected dark=<color>,light=<color>)"]
+        );
+    }
+
+    #[test]
+    fn prog_guards_keep_rules_for_matching_programs() {
+        let rules = "[fg:red,prog:^cargo$] ^warning\n[fg:blue] ^error\n";
+        let patterns = |program: &str| {
+            let mut rule_set = parse(rules);
+            rule_set.for_program(program);
+            let patterns: Vec<String> = rule_set
+                .rules()
+                .iter()
+                .map(|rule| rule.pattern().to_string())
+                .collect();
+            patterns
+        };
+        assert_eq!(patterns("cargo"), ["^warning", "^error"]);
+        assert_eq!(patterns("make"), ["^error"]);
+        assert_eq!(patterns("cargo-watch"), ["^error"]);
+    }
+
+    #[test]
+    fn loading_stops_at_a_file_that_isnt_rules() {
+        let log: String = (0..3000)
+            .map(|i| format!("[2024-01-01 12:00:00] INFO request {} served\n", i))
+            .collect();
+        let rule_set = parse(&log);
+        assert_eq!(rule_set.diagnostics.len(), MAX_ERRORS + 1);
+        let last = rule_set.diagnostics.last().unwrap();
+        assert_eq!(last.line, MAX_ERRORS + 1);
+        assert_eq!(
+            last.message,
+            "Stopped reading after 50 errors, this doesn't look like a rule file"
+        );
+    }
+
+    #[test]
+    fn min_cols_rules_are_dropped_on_narrow_terminals() {
+        let patterns = |cols: usize| {
+            let mut rule_set = parse("[fg:red,min-cols:100] wide\n[fg:red] any\n");
+            rule_set.for_width(cols);
+            let patterns: Vec<String> = rule_set
+                .rules()
+                .iter()
+                .map(|rule| rule.pattern().to_string())
+                .collect();
+            patterns
+        };
+        assert_eq!(patterns(80), ["any"]);
+        assert_eq!(patterns(100), ["wide", "any"]);
+    }
+
+    #[test]
+    fn every_needs_a_positive_count() {
+        let rule_set = parse("[fg:red,every:0] x\n[fg:red,every:two] y\n[fg:red,every:2] z\n");
+        assert_eq!(
+            problems(&rule_set),
+            [
+                "Invalid every: 0 (expected a count >= 1)",
+                "Invalid every: two (expected a count >= 1)"
+            ]
+        );
+        assert_eq!(rule_set.rules()[2].every, Some(2));
+    }
+
+    #[test]
+    fn csv_map_reports_missing_columns_and_bad_colors() {
+        let dir = std::env::temp_dir().join(format!("nscwrs-{}-csv-map", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hosts.csv"), "host,color\ndb1,red\nweb1,nope\n").unwrap();
+        let rule_set = parse_color_rules(
+            "[fg:blue,csv-map:hosts.csv:host:color] (\\S+)\n[fg:blue,csv-map:hosts.csv:name:color] x\n[fg:blue,csv-map:color] y\n",
+            &dir,
+        );
+        let hosts = dir.join("hosts.csv");
+        assert_eq!(
+            problems(&rule_set),
+            [
+                format!("Invalid color on row 3 of {}: nope", hosts.display()).as_str(),
+                "Could not read color map hosts.csv (no column named name)",
+                "Invalid csv-map: color (expected FILE:KEY-COLUMN:COLOR-COLUMN)",
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn thousands_format_groups_plain_numbers_only() {
+        let format = NumberFormat::Thousands(',');
+        assert_eq!(format.apply("1234567").as_deref(), Some("1,234,567"));
+        assert_eq!(format.apply("-98765.43").as_deref(), Some("-98,765.43"));
+        assert_eq!(format.apply("999").as_deref(), Some("999"));
+        assert_eq!(
+            NumberFormat::Thousands(' ').apply("+1000").as_deref(),
+            Some("+1 000")
+        );
+        for text in ["12a", "1.", ".5", "-", "1.2.3"] {
+            assert_eq!(format.apply(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn unknown_extends_base_is_reported_with_its_line() {
+        let rule_set = parse("[fg:red] ok\n[fg:red,extends:nope] error\n");
+        assert_eq!(problems(&rule_set), ["Unknown color variable: nope"]);
+        assert_eq!(rule_set.diagnostics[0].line, 2);
+    }
+
+    #[test]
+    fn arg_guards_keep_rules_for_matching_arguments() {
+        let patterns = |args: &[&str]| {
+            let mut rule_set = parse("[fg:red,arg:(^| )--verbose( |$)] ^DEBUG\n[fg:red] ^error\n");
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            rule_set.for_args(&args);
+            let patterns: Vec<String> = rule_set
+                .rules()
+                .iter()
+                .map(|rule| rule.pattern().to_string())
+                .collect();
+            patterns
+        };
+        assert_eq!(patterns(&["build", "--verbose"]), ["^DEBUG", "^error"]);
+        assert_eq!(patterns(&["build"]), ["^error"]);
+        assert_eq!(patterns(&["--verbose-ish"]), ["^error"]);
+    }
+
+    #[test]
+    fn unbalanced_push_and_pop_are_reported() {
+        let rule_set = parse("@pop\n@push bg:black\n[fg:red] x\n");
+        let reported: Vec<(usize, &str)> = rule_set
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(
+            reported,
+            [(1, "@pop without @push"), (2, "@push without @pop")]
+        );
+    }
+
+    #[test]
+    fn progress_gradient_runs_from_red_through_yellow_to_green() {
+        let rgb = |color| match color {
+            Color::TrueColor { r, g, b } => (r, g, b),
+            other => panic!("{:?}", other),
+        };
+        let up = Progress::RedToGreen;
+        assert_eq!(rgb(up.color(0.0)), (255, 0, 0));
+        assert_eq!(rgb(up.color(10.0)), (255, 51, 0));
+        assert_eq!(rgb(up.color(50.0)), (255, 255, 0));
+        assert_eq!(rgb(up.color(90.0)), (51, 255, 0));
+        assert_eq!(rgb(up.color(250.0)), (0, 255, 0));
+        assert_eq!(rgb(Progress::GreenToRed.color(90.0)), (255, 51, 0));
+        assert_eq!(parse_percentage("99.5 %"), Some(99.5));
+        assert_eq!(parse_percentage("n/a"), None);
+    }
+
+    #[test]
+    fn pad_widths_are_capped() {
+        let rule_set = parse("[fg:red,pad:4096] a\n[fg:red,pad:>4097] b\n[fg:red,pad:0] c\n");
+        assert_eq!(
+            problems(&rule_set),
+            [
+                "Invalid pad: >4097 (a width from 1 to 4096, or >width to right-align)",
+                "Invalid pad: 0 (a width from 1 to 4096, or >width to right-align)"
+            ]
+        );
+    }
+
+    #[test]
+    fn and_patterns_need_two_regexes_and_no_if() {
+        let rule_set = parse("[fg:red,if:x] and:a&b\n[fg:red] and:a\n[fg:red] and:a&(\n");
+        let found = problems(&rule_set);
+        assert_eq!(
+            found[..2],
+            [
+                "An and: pattern can't be combined with if:",
+                "Invalid and: pattern: a (expected and:<regex>&<regex>)",
+            ]
+        );
+        assert!(
+            found[2].starts_with("Invalid and: regex: ("),
+            "{}",
+            found[2]
+        );
+    }
+
+    #[test]
+    fn wordlists_that_cant_be_read_or_are_empty_are_reported() {
+        let dir = std::env::temp_dir().join(format!("nscwrs-{}-wordlist", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("empty"), "# nothing yet\n\n").unwrap();
+        let rule_set =
+            parse_color_rules("[fg:red] wordlist:empty\n[fg:red] wordlist:missing\n", &dir);
+        let found = problems(&rule_set);
+        assert_eq!(
+            found[0],
+            format!("Wordlist {} has no terms", dir.join("empty").display())
+        );
+        assert!(
+            found[1].starts_with(&format!(
+                "Cannot read wordlist {} (",
+                dir.join("missing").display()
+            )),
+            "{}",
+            found[1]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,75 @@
+//! `--syntax`: output highlighted as source code with syntect, before the rules run.
+
+use std::sync::OnceLock;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+
+/// The theme the highlighted lines are colored with
+const THEME: &str = "base16-ocean.dark";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_nonewlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    &THEMES.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
+
+/// Highlights one line after another as a language; the state carries over from
+/// line to line, e.g. for comments that span several
+pub struct SyntaxHighlighter {
+    lines: HighlightLines<'static>,
+}
+
+impl SyntaxHighlighter {
+    /// Highlighter for `lang`, a language name or file extension (`rust`, `py`),
+    /// or `None` when syntect doesn't know it
+    pub fn new(lang: &str) -> Option<Self> {
+        let syntaxes = syntaxes();
+        let syntax = syntaxes
+            .find_syntax_by_token(lang)
+            .or_else(|| syntaxes.find_syntax_by_extension(&lang.to_lowercase()))?;
+        Some(SyntaxHighlighter {
+            lines: HighlightLines::new(syntax, theme()),
+        })
+    }
+
+    /// `line` in 24-bit color escapes, or as it was when it couldn't be highlighted
+    pub fn highlight(&mut self, line: &str) -> String {
+        match self.lines.highlight_line(line, syntaxes()) {
+            Ok(ranges) if !ranges.is_empty() => {
+                format!("{}\x1b[0m", as_24_bit_terminal_escaped(&ranges, false))
+            }
+            _ => line.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knows_languages_by_name_and_extension() {
+        assert!(SyntaxHighlighter::new("rust").is_some());
+        assert!(SyntaxHighlighter::new("py").is_some());
+        assert!(SyntaxHighlighter::new("no-such-language").is_none());
+    }
+
+    #[test]
+    fn highlights_keep_the_text() {
+        let mut highlighter = SyntaxHighlighter::new("rs").unwrap();
+        let line = highlighter.highlight("fn main() {}");
+        assert!(line.contains("\x1b[38;2;"));
+        assert!(line.ends_with("\x1b[0m"));
+        let plain = regex::Regex::new("\x1b\\[[0-9;]*m")
+            .unwrap()
+            .replace_all(&line, "");
+        assert_eq!(plain, "fn main() {}");
+    }
+}